@@ -0,0 +1,491 @@
+use std::path::Path;
+use regex::Regex;
+
+/// something defined in a source file, e.g. a function or a struct
+#[derive(Debug,Clone,PartialEq)]
+pub struct Definition {
+    pub name: String,
+    pub kind: &'static str,
+    pub line: usize,
+    pub column: usize,
+    pub scope: Option<String>, // enclosing definitions, e.g. the struct an impl is for
+}
+
+#[derive(Debug,Clone)]
+pub struct Analysis {
+    pub language: &'static str,
+    pub definitions: Vec<Definition>,
+    pub imports: Vec<String>,
+}
+
+impl Analysis {
+    pub fn new(language: &'static str) -> Analysis {
+        Analysis {
+            language: language,
+            definitions: vec![],
+            imports: vec![],
+        }
+    }
+
+    /// the text stored in the keywords field.
+    /// identifiers that are merely used are already searchable in the file text,
+    /// so only definitions and imports go in here, which makes files that define a name
+    /// score higher than files that only mention it
+    pub fn keywords(&self) -> String {
+        let mut keywords: Vec<&str> = self.definitions.iter().map(|d| &d.name[..]).collect();
+
+        keywords.extend(self.imports.iter().map(|i| &i[..]));
+
+        keywords.join("\n")
+    }
+}
+
+/// extracts definitions and imports from the source of one language
+pub trait Analyser {
+    fn language(&self) -> &'static str;
+
+    fn handles_extension(&self, extension: &str) -> bool;
+
+    /// e.g. python3 from a #!/usr/bin/env python3 line
+    fn handles_interpreter(&self, interpreter: &str) -> bool;
+
+    fn analyse(&self, text: &str) -> Analysis;
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Scoping {
+    Braces,
+    Indent,
+}
+
+pub struct DefinitionPattern {
+    pub kind: &'static str,
+    pub container: bool, // whether definitions inside it are scoped by it
+    pub regex: Regex,
+}
+
+impl DefinitionPattern {
+    /// the pattern is matched against each line, and its first capture group is the name
+    pub fn new(kind: &'static str, container: bool, pattern: &str) -> DefinitionPattern {
+        DefinitionPattern {
+            kind: kind,
+            container: container,
+            regex: Regex::new(pattern).unwrap(),
+        }
+    }
+}
+
+struct OpenScope {
+    name: String,
+    depth: usize,
+    opened: bool,
+}
+
+/// a line by line analyser driven by regexes.
+/// it doesn't parse anything, so it's fooled by braces in strings and comments,
+/// but it's good enough to tell definitions from mentions
+pub struct PatternAnalyser {
+    pub language: &'static str,
+    pub extensions: Vec<&'static str>,
+    pub interpreters: Vec<&'static str>,
+    pub scoping: Scoping,
+    pub scope_separator: &'static str,
+    pub definitions: Vec<DefinitionPattern>, // the first that matches a line wins
+    pub imports: Vec<Regex>,
+    pub reserved: Vec<&'static str>, // words that patterns might mistake for names
+}
+
+impl PatternAnalyser {
+    fn scope_name(&self, scopes: &Vec<OpenScope>) -> Option<String> {
+        if scopes.is_empty() {
+            None
+        } else {
+            let names: Vec<&str> = scopes.iter().map(|s| &s.name[..]).collect();
+            Some(names.join(self.scope_separator))
+        }
+    }
+
+    fn find_definition<'a>(&self, line: &'a str) -> Option<(&DefinitionPattern, &'a str, usize)> {
+        for pattern in self.definitions.iter() {
+            match pattern.regex.captures(line).and_then(|caps| caps.pos(1)) {
+                Some((start, end)) => {
+                    let name = &line[start..end];
+
+                    if self.reserved.contains(&name) {
+                        return None;
+                    }
+
+                    return Some((pattern, name, start));
+                },
+                None => {}
+            }
+        }
+
+        None
+    }
+}
+
+impl Analyser for PatternAnalyser {
+    fn language(&self) -> &'static str {
+        self.language
+    }
+
+    fn handles_extension(&self, extension: &str) -> bool {
+        self.extensions.contains(&extension)
+    }
+
+    fn handles_interpreter(&self, interpreter: &str) -> bool {
+        self.interpreters.iter().any(|i| interpreter.starts_with(i))
+    }
+
+    fn analyse(&self, text: &str) -> Analysis {
+        let mut analysis = Analysis::new(self.language);
+        let mut scopes: Vec<OpenScope> = vec![];
+        let mut depth = 0;
+
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let indent = line.len() - line.trim_left().len();
+
+            if self.scoping == Scoping::Indent {
+                while scopes.last().map(|s| indent <= s.depth).unwrap_or(false) {
+                    scopes.pop();
+                }
+            }
+
+            for import in self.imports.iter() {
+                for caps in import.captures_iter(line) {
+                    match caps.at(1) {
+                        Some(name) => analysis.imports.push(name.trim().to_owned()),
+                        None => {}
+                    }
+                }
+            }
+
+            let mut container = None;
+
+            match self.find_definition(line) {
+                Some((pattern, name, start)) => {
+                    analysis.definitions.push(Definition {
+                        name: name.to_owned(),
+                        kind: pattern.kind,
+                        line: i + 1,
+                        column: start + 1,
+                        scope: self.scope_name(&scopes),
+                    });
+
+                    if pattern.container {
+                        container = Some(name.to_owned());
+                    }
+                },
+                None => {}
+            }
+
+            match self.scoping {
+                Scoping::Braces => {
+                    let before = depth;
+                    let opens = line.matches('{').count();
+                    let closes = line.matches('}').count();
+
+                    depth = (depth + opens).saturating_sub(closes);
+
+                    match container {
+                        // a body opened on this line, or will presumably open on the next one
+                        Some(name) => if depth > before || (opens == 0 && !line.trim_right().ends_with(';')) {
+                            scopes.push(OpenScope { name: name, depth: before, opened: false });
+                        },
+                        None => {}
+                    }
+
+                    for scope in scopes.iter_mut() {
+                        if depth > scope.depth {
+                            scope.opened = true;
+                        }
+                    }
+
+                    while scopes.last().map(|s| s.opened && depth <= s.depth).unwrap_or(false) {
+                        scopes.pop();
+                    }
+                },
+                Scoping::Indent => {
+                    match container {
+                        Some(name) => scopes.push(OpenScope { name: name, depth: indent, opened: true }),
+                        None => {}
+                    }
+                }
+            }
+        }
+
+        analysis
+    }
+}
+
+fn regexes(patterns: &[&str]) -> Vec<Regex> {
+    patterns.iter().map(|p| Regex::new(p).unwrap()).collect()
+}
+
+pub fn rust() -> PatternAnalyser {
+    PatternAnalyser {
+        language: "rust",
+        extensions: vec!["rs"],
+        interpreters: vec![],
+        scoping: Scoping::Braces,
+        scope_separator: "::",
+        definitions: vec![
+            DefinitionPattern::new("function", false, r"^\s*(?:pub(?:\([^)]*\))?\s+)?(?:(?:const|unsafe|extern\s+\x22[^\x22]*\x22)\s+)*fn\s+([A-Za-z_][A-Za-z0-9_]*)"),
+            DefinitionPattern::new("struct", true, r"^\s*(?:pub(?:\([^)]*\))?\s+)?struct\s+([A-Za-z_][A-Za-z0-9_]*)"),
+            DefinitionPattern::new("enum", true, r"^\s*(?:pub(?:\([^)]*\))?\s+)?enum\s+([A-Za-z_][A-Za-z0-9_]*)"),
+            DefinitionPattern::new("trait", true, r"^\s*(?:pub(?:\([^)]*\))?\s+)?(?:unsafe\s+)?trait\s+([A-Za-z_][A-Za-z0-9_]*)"),
+            DefinitionPattern::new("impl", true, r"^\s*(?:unsafe\s+)?impl(?:<[^>]*>)?\s+(?:[A-Za-z0-9_:<>, ]+\s+for\s+)?([A-Za-z_][A-Za-z0-9_]*)"),
+            DefinitionPattern::new("module", true, r"^\s*(?:pub(?:\([^)]*\))?\s+)?mod\s+([A-Za-z_][A-Za-z0-9_]*)"),
+            DefinitionPattern::new("type", false, r"^\s*(?:pub(?:\([^)]*\))?\s+)?type\s+([A-Za-z_][A-Za-z0-9_]*)"),
+            DefinitionPattern::new("constant", false, r"^\s*(?:pub(?:\([^)]*\))?\s+)?(?:const|static)\s+(?:mut\s+)?([A-Za-z_][A-Za-z0-9_]*)\s*:"),
+            DefinitionPattern::new("macro", false, r"^\s*macro_rules!\s*([A-Za-z_][A-Za-z0-9_]*)"),
+        ],
+        imports: regexes(&[
+            r"^\s*(?:pub\s+)?use\s+([^;]+);",
+            r"^\s*extern\s+crate\s+([A-Za-z_][A-Za-z0-9_]*)",
+        ]),
+        reserved: vec![],
+    }
+}
+
+pub fn python() -> PatternAnalyser {
+    PatternAnalyser {
+        language: "python",
+        extensions: vec!["py", "pyw"],
+        interpreters: vec!["python"],
+        scoping: Scoping::Indent,
+        scope_separator: ".",
+        definitions: vec![
+            DefinitionPattern::new("class", true, r"^\s*class\s+([A-Za-z_][A-Za-z0-9_]*)"),
+            DefinitionPattern::new("function", true, r"^\s*(?:async\s+)?def\s+([A-Za-z_][A-Za-z0-9_]*)"),
+            DefinitionPattern::new("variable", false, r"^([A-Za-z_][A-Za-z0-9_]*)\s*=[^=]"),
+        ],
+        imports: regexes(&[
+            r"^\s*import\s+([A-Za-z0-9_., ]+)",
+            r"^\s*from\s+([A-Za-z0-9_.]+)\s+import",
+        ]),
+        reserved: vec![],
+    }
+}
+
+fn javascript_like(language: &'static str, extensions: Vec<&'static str>) -> PatternAnalyser {
+    PatternAnalyser {
+        language: language,
+        extensions: extensions,
+        interpreters: vec!["node"],
+        scoping: Scoping::Braces,
+        scope_separator: ".",
+        definitions: vec![
+            DefinitionPattern::new("function", false, r"^\s*(?:export\s+)?(?:default\s+)?(?:async\s+)?function\s*\*?\s*([A-Za-z_$][A-Za-z0-9_$]*)"),
+            DefinitionPattern::new("class", true, r"^\s*(?:export\s+)?(?:default\s+)?(?:abstract\s+)?class\s+([A-Za-z_$][A-Za-z0-9_$]*)"),
+            DefinitionPattern::new("interface", true, r"^\s*(?:export\s+)?interface\s+([A-Za-z_$][A-Za-z0-9_$]*)"),
+            DefinitionPattern::new("enum", true, r"^\s*(?:export\s+)?(?:const\s+)?enum\s+([A-Za-z_$][A-Za-z0-9_$]*)"),
+            DefinitionPattern::new("type", false, r"^\s*(?:export\s+)?type\s+([A-Za-z_$][A-Za-z0-9_$]*)\s*(?:<[^>]*>)?\s*="),
+            DefinitionPattern::new("variable", false, r"^\s*(?:export\s+)?(?:const|let|var)\s+([A-Za-z_$][A-Za-z0-9_$]*)\s*(?::[^=]+)?="),
+            DefinitionPattern::new("method", false, r"^\s+(?:(?:public|private|protected|static|async|get|set|readonly)\s+)*([A-Za-z_$][A-Za-z0-9_$]*)\s*\([^)]*\)\s*(?::[^{]+)?\{"),
+        ],
+        imports: regexes(&[
+            r"^\s*import\s+.*from\s+['\x22]([^'\x22]+)['\x22]",
+            r"^\s*import\s+['\x22]([^'\x22]+)['\x22]",
+            r"require\(\s*['\x22]([^'\x22]+)['\x22]\s*\)",
+        ]),
+        reserved: vec!["if", "for", "while", "switch", "catch", "function", "return", "with"],
+    }
+}
+
+pub fn javascript() -> PatternAnalyser {
+    javascript_like("javascript", vec!["js", "jsx", "mjs"])
+}
+
+pub fn typescript() -> PatternAnalyser {
+    javascript_like("typescript", vec!["ts", "tsx"])
+}
+
+pub fn go() -> PatternAnalyser {
+    PatternAnalyser {
+        language: "go",
+        extensions: vec!["go"],
+        interpreters: vec![],
+        scoping: Scoping::Braces,
+        scope_separator: ".",
+        definitions: vec![
+            DefinitionPattern::new("function", false, r"^func\s+(?:\([^)]*\)\s*)?([A-Za-z_][A-Za-z0-9_]*)"),
+            DefinitionPattern::new("struct", false, r"^type\s+([A-Za-z_][A-Za-z0-9_]*)\s+struct"),
+            DefinitionPattern::new("interface", false, r"^type\s+([A-Za-z_][A-Za-z0-9_]*)\s+interface"),
+            DefinitionPattern::new("type", false, r"^type\s+([A-Za-z_][A-Za-z0-9_]*)"),
+            DefinitionPattern::new("variable", false, r"^(?:var|const)\s+([A-Za-z_][A-Za-z0-9_]*)"),
+        ],
+        imports: regexes(&[
+            r"^import\s+(?:[A-Za-z_.]+\s+)?\x22([^\x22]+)\x22",
+            // inside an import ( ... ) block
+            r"^\s+(?:[A-Za-z_.]+\s+)?\x22([^\x22]+)\x22\s*$",
+        ]),
+        reserved: vec![],
+    }
+}
+
+pub fn java() -> PatternAnalyser {
+    PatternAnalyser {
+        language: "java",
+        extensions: vec!["java"],
+        interpreters: vec![],
+        scoping: Scoping::Braces,
+        scope_separator: ".",
+        definitions: vec![
+            DefinitionPattern::new("class", true, r"^\s*(?:(?:public|protected|private|static|final|abstract)\s+)*class\s+([A-Za-z_][A-Za-z0-9_]*)"),
+            DefinitionPattern::new("interface", true, r"^\s*(?:(?:public|protected|private|static|abstract)\s+)*@?interface\s+([A-Za-z_][A-Za-z0-9_]*)"),
+            DefinitionPattern::new("enum", true, r"^\s*(?:(?:public|protected|private|static)\s+)*enum\s+([A-Za-z_][A-Za-z0-9_]*)"),
+            DefinitionPattern::new("method", false, r"^\s*(?:(?:public|protected|private|static|final|abstract|synchronized|native|default)\s+)+(?:<[^>]*>\s+)?[A-Za-z0-9_<>\[\]., ?]+\s+([A-Za-z_][A-Za-z0-9_]*)\s*\("),
+        ],
+        imports: regexes(&[
+            r"^\s*import\s+(?:static\s+)?([A-Za-z0-9_.*]+)\s*;",
+            r"^\s*package\s+([A-Za-z0-9_.]+)\s*;",
+        ]),
+        reserved: vec!["if", "for", "while", "switch", "catch", "return", "new"],
+    }
+}
+
+pub fn c() -> PatternAnalyser {
+    PatternAnalyser {
+        language: "c",
+        extensions: vec!["c", "h"],
+        interpreters: vec![],
+        scoping: Scoping::Braces,
+        scope_separator: ".",
+        definitions: vec![
+            DefinitionPattern::new("macro", false, r"^\s*#\s*define\s+([A-Za-z_][A-Za-z0-9_]*)"),
+            DefinitionPattern::new("struct", false, r"^\s*(?:typedef\s+)?struct\s+([A-Za-z_][A-Za-z0-9_]*)\s*\{?\s*$"),
+            DefinitionPattern::new("enum", false, r"^\s*(?:typedef\s+)?enum\s+([A-Za-z_][A-Za-z0-9_]*)\s*\{?\s*$"),
+            DefinitionPattern::new("union", false, r"^\s*(?:typedef\s+)?union\s+([A-Za-z_][A-Za-z0-9_]*)\s*\{?\s*$"),
+            DefinitionPattern::new("type", false, r"^typedef\s+.*[\s*}]([A-Za-z_][A-Za-z0-9_]*)\s*;"),
+            // a definition starts in the first column and isn't followed by a semicolon
+            DefinitionPattern::new("function", false, r"^[A-Za-z_][A-Za-z0-9_ \t*]*[\s*]([A-Za-z_][A-Za-z0-9_]*)\s*\([^;]*$"),
+        ],
+        imports: regexes(&[
+            r"^\s*#\s*include\s*[<\x22]([^>\x22]+)[>\x22]",
+        ]),
+        reserved: vec!["if", "for", "while", "switch", "return", "sizeof"],
+    }
+}
+
+/// the interpreter named by a #! line, e.g. python3 for #!/usr/bin/env python3
+pub fn shebang_interpreter(text: &str) -> Option<&str> {
+    let first_line = match text.lines().next() {
+        Some(line) if line.starts_with("#!") => &line[2..],
+        _ => return None,
+    };
+
+    let mut words = first_line.split_whitespace();
+
+    let program = match words.next() {
+        Some(program) => program.rsplit('/').next().unwrap_or(program),
+        None => return None,
+    };
+
+    if program == "env" {
+        words.next()
+    } else {
+        Some(program)
+    }
+}
+
+/// picks an analyser for each file by its extension, or failing that its #! line
+pub struct Analysers {
+    analysers: Vec<Box<Analyser>>,
+}
+
+impl Analysers {
+    pub fn new() -> Analysers {
+        Analysers {
+            analysers: vec![],
+        }
+    }
+
+    /// the analysers for all the languages supported out of the box
+    pub fn new_with_defaults() -> Analysers {
+        let mut analysers = Analysers::new();
+
+        analysers.register(Box::new(rust()));
+        analysers.register(Box::new(python()));
+        analysers.register(Box::new(javascript()));
+        analysers.register(Box::new(typescript()));
+        analysers.register(Box::new(go()));
+        analysers.register(Box::new(java()));
+        analysers.register(Box::new(c()));
+
+        analysers
+    }
+
+    pub fn register(&mut self, analyser: Box<Analyser>) {
+        self.analysers.push(analyser);
+    }
+
+    pub fn find(&self, path: &Path, text: &str) -> Option<&Analyser> {
+        let by_extension = path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| self.analysers.iter().find(|a| a.handles_extension(ext)));
+
+        match by_extension {
+            Some(analyser) => Some(&**analyser),
+            None => {
+                shebang_interpreter(text)
+                    .and_then(|interpreter| self.analysers.iter().find(|a| a.handles_interpreter(interpreter)))
+                    .map(|analyser| &**analyser)
+            }
+        }
+    }
+
+    pub fn analyse(&self, path: &Path, text: &str) -> Option<Analysis> {
+        self.find(path, text).map(|analyser| analyser.analyse(text))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    pub fn test_rust_definitions_and_scopes() {
+        let text = "use std::path::Path;\n\
+                    pub struct Repo {\n    path: String,\n}\n\
+                    impl Repo {\n    pub fn open(&self) {\n        if true {\n        }\n    }\n}\n\
+                    fn main() {\n}\n";
+
+        let analysis = rust().analyse(text);
+
+        let names: Vec<(&str, &str, Option<String>)> = analysis.definitions.iter()
+            .map(|d| (&d.name[..], d.kind, d.scope.clone()))
+            .collect();
+
+        assert_eq!(names, vec![
+            ("Repo", "struct", None),
+            ("Repo", "impl", None),
+            ("open", "function", Some("Repo".to_owned())),
+            ("main", "function", None),
+        ]);
+        assert_eq!(analysis.imports, vec!["std::path::Path".to_owned()]);
+        assert_eq!(analysis.definitions[2].line, 6);
+        assert_eq!(analysis.definitions[2].column, 12);
+    }
+
+    #[test]
+    pub fn test_python_found_by_shebang() {
+        let text = "#!/usr/bin/env python3\nimport os\n\nclass Worker:\n    def run(self):\n        pass\n\ndef main():\n    pass\n";
+
+        let analysers = Analysers::new_with_defaults();
+        let analysis = analysers.analyse(Path::new("bin/worker"), text).unwrap();
+
+        assert_eq!(analysis.language, "python");
+        assert_eq!(analysis.imports, vec!["os".to_owned()]);
+        assert_eq!(analysis.definitions[1].name, "run");
+        assert_eq!(analysis.definitions[1].scope, Some("Worker".to_owned()));
+        assert_eq!(analysis.definitions[2].scope, None);
+        assert_eq!(analysis.keywords(), "Worker\nrun\nmain\nos");
+    }
+}
@@ -0,0 +1,328 @@
+use std::path::PathBuf;
+use std::collections::{BTreeMap,HashSet};
+use std::io::Read;
+use hyper;
+use rustc_serialize::Encodable;
+use rustc_serialize::json;
+use rustc_serialize::json::{Json,ToJson};
+use time;
+use config::IndexConfig;
+use db::Db;
+use result::*;
+
+/// what to record in the db once elasticsearch has accepted a document
+#[derive(Debug,Clone)]
+pub enum BulkDone {
+    Commit(String),
+    CommitDeleted(String),
+    File(String, PathBuf, String, String, usize), // branch, path, commit id, blob id, symbol count
+    FileDeleted(String, PathBuf),
+    Nothing,
+}
+
+#[derive(Debug,Clone)]
+struct BulkAction {
+    header: String,
+    source: Option<String>,
+    done: BulkDone,
+    group: Option<usize>,
+}
+
+/// queues up index and delete operations and sends them to elasticsearch's _bulk api
+/// once there are enough of them or enough time has passed.
+/// items that elasticsearch rejects are logged and left un-marked in the db,
+/// so they're picked up again next sync.
+/// actions can be grouped, so that e.g. a file is only marked indexed
+/// once its document and the cleanup of its previous version have both been accepted
+pub struct Bulk {
+    url: String,
+    index: String,
+    batch_size: usize,
+    flush_interval_secs: i64,
+    actions: Vec<BulkAction>,
+    last_flush: i64,
+    failures: usize,
+    group: Option<usize>,
+    next_group: usize,
+}
+
+impl Bulk {
+    pub fn new(es_base_url: &str, index: &str, index_config: &IndexConfig) -> Bulk {
+        Bulk {
+            url: format!("{}/_bulk", es_base_url),
+            index: index.to_owned(),
+            batch_size: index_config.bulk_batch_size,
+            flush_interval_secs: index_config.bulk_flush_interval_secs as i64,
+            actions: vec![],
+            last_flush: time::get_time().sec,
+            failures: 0,
+            group: None,
+            next_group: 0,
+        }
+    }
+
+    fn header(&self, action: &str, doc_type: &str, id: &str) -> String {
+        let mut meta = BTreeMap::new();
+        meta.insert("_index".to_string(), self.index.to_json());
+        meta.insert("_type".to_string(), doc_type.to_json());
+        meta.insert("_id".to_string(), id.to_json());
+
+        let mut header = BTreeMap::new();
+        header.insert(action.to_string(), Json::Object(meta));
+
+        Json::Object(header).to_string()
+    }
+
+    pub fn index<T: Encodable>(&mut self, doc_type: &str, id: &str, doc: &T, done: BulkDone) -> RepoResult<()> {
+        let source = try!(json::encode(doc).map_err(|e| RepoError::JsonEncodeError(format!("{:?}", e))));
+
+        let header = self.header("index", doc_type, id);
+
+        self.push(header, Some(source), done);
+
+        Ok(())
+    }
+
+    /// merge fields into an existing document
+    pub fn update(&mut self, doc_type: &str, id: &str, partial: Json, done: BulkDone) {
+        let mut source = BTreeMap::new();
+        source.insert("doc".to_string(), partial);
+
+        let header = self.header("update", doc_type, id);
+
+        self.push(header, Some(Json::Object(source).to_string()), done);
+    }
+
+    pub fn delete(&mut self, doc_type: &str, id: &str, done: BulkDone) {
+        let header = self.header("delete", doc_type, id);
+
+        self.push(header, None, done);
+    }
+
+    fn push(&mut self, header: String, source: Option<String>, done: BulkDone) {
+        let group = self.group;
+
+        self.actions.push(BulkAction {
+            header: header,
+            source: source,
+            done: done,
+            group: group,
+        });
+    }
+
+    /// the actions queued from now until end_group succeed or fail together.
+    /// they aren't split across batches
+    pub fn begin_group(&mut self) {
+        self.group = Some(self.next_group);
+        self.next_group += 1;
+    }
+
+    /// record done once every action in the group has been accepted,
+    /// or straight away if nothing was queued in it
+    pub fn end_group(&mut self, db: &Db, repo_id: &str, done: BulkDone) -> RepoResult<()> {
+        let group = self.group.take();
+
+        let queued = group.is_some() && self.actions.last().map(|a| a.group == group).unwrap_or(false);
+
+        if queued {
+            self.actions.last_mut().unwrap().done = done;
+        } else {
+            try!(Bulk::mark_done(db, repo_id, &done));
+        }
+
+        self.maybe_flush(db, repo_id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    pub fn needs_flush(&self) -> bool {
+        self.group.is_none() && (self.actions.len() >= self.batch_size ||
+            (!self.actions.is_empty() && time::get_time().sec - self.last_flush >= self.flush_interval_secs))
+    }
+
+    /// number of items rejected since this was last called
+    pub fn take_failures(&mut self) -> usize {
+        let failures = self.failures;
+        self.failures = 0;
+        failures
+    }
+
+    /// send a batch if it's full or the flush interval has passed
+    pub fn maybe_flush(&mut self, db: &Db, repo_id: &str) -> RepoResult<()> {
+        if self.needs_flush() {
+            self.flush(db, repo_id)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// send everything queued so far
+    pub fn flush(&mut self, db: &Db, repo_id: &str) -> RepoResult<()> {
+        self.last_flush = time::get_time().sec;
+
+        if self.actions.is_empty() {
+            return Ok(());
+        }
+
+        let actions: Vec<BulkAction> = self.actions.drain(..).collect();
+
+        let mut body = String::new();
+        for action in actions.iter() {
+            body.push_str(&action.header);
+            body.push('\n');
+
+            match action.source {
+                Some(ref source) => {
+                    body.push_str(source);
+                    body.push('\n');
+                },
+                None => {}
+            }
+        }
+
+        info!("sending bulk request of {} items", actions.len());
+
+        let client = hyper::Client::new();
+        let mut res = try!(client.post(&self.url).body(&body[..]).send());
+
+        let mut res_str = String::new();
+        try!(res.read_to_string(&mut res_str));
+
+        let res_json = try!(Json::from_str(&res_str).map_err(|e| RepoError::ElasticSearchResponseError(format!("{:?}", e))));
+
+        let items = try!(res_json.find("items")
+                         .and_then(|i| i.as_array())
+                         .ok_or(RepoError::ElasticSearchResponseError(res_str.clone())));
+
+        let mut succeeded = vec![];
+
+        for (i, action) in actions.iter().enumerate() {
+            match items.get(i) {
+                Some(item) if Bulk::item_succeeded(item) => {
+                    succeeded.push(true);
+                },
+                item => {
+                    error!("elasticsearch rejected bulk item {}: {:?}", action.header, item);
+                    self.failures += 1;
+                    succeeded.push(false);
+                }
+            }
+        }
+
+        for done in Bulk::completed(&actions, &succeeded) {
+            try!(Bulk::mark_done(db, repo_id, done));
+        }
+
+        Ok(())
+    }
+
+    /// what to record for the accepted actions.
+    /// an action in a group only counts if the rest of its group was accepted too
+    fn completed<'a>(actions: &'a [BulkAction], succeeded: &[bool]) -> Vec<&'a BulkDone> {
+        let failed_groups: HashSet<usize> = actions.iter().zip(succeeded.iter())
+            .filter(|&(_, ok)| !*ok)
+            .filter_map(|(action, _)| action.group)
+            .collect();
+
+        actions.iter().zip(succeeded.iter())
+            .filter(|&(action, ok)| *ok && action.group.map(|g| !failed_groups.contains(&g)).unwrap_or(true))
+            .map(|(action, _)| &action.done)
+            .collect()
+    }
+
+    /// each item looks like {"index": {"_id": ..., "status": 201, "error": {"type": ..., "reason": ...}}}
+    fn item_succeeded(item: &Json) -> bool {
+        let result = item.as_object().and_then(|o| o.iter().next());
+
+        match result {
+            Some((action, result)) => {
+                let status = result.find("status").and_then(|s| s.as_u64()).unwrap_or(500);
+
+                // deleting something that's already gone is fine, and so is updating it,
+                // e.g. taking a branch off a binary file that never had a document.
+                // but not if it's the index that's missing
+                let missing_ok = status == 404 && match result.find("error") {
+                    None => action == "delete",
+                    Some(error) => action == "update" && error.find("type").and_then(|t| t.as_string()) == Some("document_missing_exception"),
+                };
+
+                missing_ok || (result.find("error").is_none() && status < 300)
+            },
+            None => false
+        }
+    }
+
+    fn mark_done(db: &Db, repo_id: &str, done: &BulkDone) -> RepoResult<()> {
+        match *done {
+            BulkDone::Commit(ref commit_id) => db.mark_commit_as_indexed(repo_id, commit_id),
+            BulkDone::CommitDeleted(ref commit_id) => db.delete_commit(repo_id, commit_id),
+            BulkDone::File(ref branch, ref path, ref commit_id, ref blob_id, symbol_count) => db.mark_file_as_indexed(repo_id, branch, path, commit_id, Some(blob_id), symbol_count),
+            BulkDone::FileDeleted(ref branch, ref path) => db.delete_file(repo_id, branch, path),
+            BulkDone::Nothing => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+    use rustc_serialize::json::Json;
+
+    fn action(done: BulkDone, group: Option<usize>) -> BulkAction {
+        BulkAction {
+            header: String::new(),
+            source: None,
+            done: done,
+            group: group,
+        }
+    }
+
+    #[test]
+    pub fn test_completed_waits_for_group() {
+        let actions = vec![
+            action(BulkDone::Nothing, Some(0)), // a symbol
+            action(BulkDone::Nothing, Some(0)), // a leftover symbol's removal
+            action(BulkDone::File("master".to_string(), PathBuf::from("src/main.rs"), "c1".to_string(), "b1".to_string(), 1), Some(0)),
+            action(BulkDone::Commit("c2".to_string()), None),
+        ];
+
+        // the leftover symbol wasn't removed, so the file isn't marked indexed with its new symbol count
+        let done = Bulk::completed(&actions, &[true, false, true, true]);
+        assert_eq!(done.len(), 1);
+        match *done[0] {
+            BulkDone::Commit(ref commit_id) => assert_eq!(commit_id, "c2"),
+            ref other => panic!("expected the commit, got {:?}", other),
+        }
+
+        let done = Bulk::completed(&actions, &[true, true, true, false]);
+        assert_eq!(done.len(), 3);
+        match *done[2] {
+            BulkDone::File(_, _, _, _, symbol_count) => assert_eq!(symbol_count, 1),
+            ref other => panic!("expected the file, got {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn test_item_succeeded() {
+        let created = Json::from_str(r#"{"index": {"_id": "1", "status": 201}}"#).unwrap();
+        let rejected = Json::from_str(r#"{"index": {"_id": "2", "status": 400, "error": "MapperParsingException"}}"#).unwrap();
+        let already_deleted = Json::from_str(r#"{"delete": {"_id": "3", "status": 404, "found": false}}"#).unwrap();
+
+        assert!(Bulk::item_succeeded(&created));
+        assert!(!Bulk::item_succeeded(&rejected));
+        assert!(Bulk::item_succeeded(&already_deleted));
+
+        let missing = Json::from_str(r#"{"update": {"_id": "4", "status": 404, "error": {"type": "document_missing_exception", "reason": "[file][4]: document missing"}}}"#).unwrap();
+        let index_missing = Json::from_str(r#"{"index": {"_id": "5", "status": 404, "error": {"type": "index_not_found_exception", "reason": "no such index"}}}"#).unwrap();
+        let update_index_missing = Json::from_str(r#"{"update": {"_id": "6", "status": 404, "error": {"type": "index_not_found_exception", "reason": "no such index"}}}"#).unwrap();
+        let delete_index_missing = Json::from_str(r#"{"delete": {"_id": "7", "status": 404, "error": {"type": "index_not_found_exception", "reason": "no such index"}}}"#).unwrap();
+
+        assert!(Bulk::item_succeeded(&missing));
+        assert!(!Bulk::item_succeeded(&index_missing));
+        assert!(!Bulk::item_succeeded(&update_index_missing));
+        assert!(!Bulk::item_succeeded(&delete_index_missing));
+    }
+}
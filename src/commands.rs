@@ -1,13 +1,22 @@
-use std::path::Path;
+use std::path::{Path,PathBuf};
+use std::fs;
 
 use config::Config;
-use db::Db;
+use db::{Db,RepoBranch};
 use result::*;
 use repo::*;
 use index::*;
+use worker::Worker;
+use es_schema::EsSchema;
+use zk::Zk;
+use search;
 
-fn open_db(config: &Config) -> RepoResult<Db> {
-    let dbpath = Path::new(&config.data_dir).join("db.sqlite");
+pub fn db_path(config: &Config) -> PathBuf {
+    Path::new(&config.data_dir).join("db.sqlite")
+}
+
+pub fn open_db(config: &Config) -> RepoResult<Db> {
+    let dbpath = db_path(config);
     info!("opening db");
     let database = try!(Db::open(dbpath.as_path()).map_err(|e| RepoError::SqlError(e)));
     database.migrate();
@@ -33,41 +42,142 @@ pub fn init(config: &Config) -> RepoResult<()> {
 /// 8. spider the entire repo and add all the files to the index, replacing any existing docs in index
 
 
-fn ensure_cloned(_config: &Config, db: &Db, repo: &mut Repo) -> RepoResult<()> {
+/// run one phase of a sync, recording in the db when it starts and whether it finished or failed
+fn sync_phase<F>(db: &Db, repo: &mut Repo, running: SyncState, done: SyncState, failed: SyncState, f: F) -> RepoResult<()>
+    where F: FnOnce(&mut Repo) -> RepoResult<()>
+{
+    try!(repo.transition(db, running));
+
+    match f(repo) {
+        Ok(()) => {
+            repo.transition(db, done)
+        },
+        Err(err) => {
+            error!("{:?} failed for {}: {:?}", running, repo.uri, err);
+            try!(repo.transition(db, failed));
+            Err(err)
+        }
+    }
+}
+
+/// open the clone, checking it still points at the right remote.
+/// if it had to be repaired, it needs fetching again before anything is indexed.
+/// a clone that can't be opened, or has lost commits that were indexed, is corrupted
+fn open_cloned_repo(db: &Db, repo: &mut Repo) -> RepoResult<()> {
+    match repo.open_repo() {
+        Ok(()) => {},
+        Err(err) => {
+            try!(repo.transition(db, SyncState::StartFail));
+            return Err(RepoError::CorruptedRepo(format!("can't open clone: {:?}", err)));
+        }
+    }
+
+    try!(repo.check_indexed_commits_present());
+
+    if try!(repo.repair_remote()) {
+        try!(repo.transition(db, SyncState::RemoteRepaired));
+    }
+
+    repo.track_refs(db)
+}
+
+pub fn ensure_cloned(_config: &Config, db: &Db, repo: &mut Repo) -> RepoResult<()> {
     info!("ensuring cloned {:?}", repo);
-    let _git_repo = try!(repo.clone_repo());
 
-    try!(repo.revwalk(db));
-    
-    repo.update_repo_in_db(db)
+    match repo.sync_state {
+        SyncState::Cloning | SyncState::CloneFail => {
+            if fs::metadata(&repo.path).is_ok() {
+                info!("removing incomplete clone at {:?}", repo.path);
+                try!(fs::remove_dir_all(&repo.path));
+            }
+        },
+        SyncState::Corrupted => {
+            try!(repo.move_aside());
+        },
+        _ => {}
+    }
+
+    sync_phase(db, repo, SyncState::Cloning, SyncState::Cloned, SyncState::CloneFail, |repo| {
+        try!(repo.clone_repo());
+
+        try!(repo.track_refs(db));
+
+        try!(repo.repoint_branches_to_origin());
+
+        // the branches keep their indexed commits, so only what's changed since is indexed,
+        // unless the commits have since been rewritten upstream
+        try!(repo.forget_missing_indexed_commits(db));
+
+        repo.revwalk(db)
+    })
 }
 
-fn ensure_fetched(config: &Config, db: &Db, repo: &mut Repo) -> RepoResult<()> {
+pub fn ensure_fetched(config: &Config, db: &Db, repo: &mut Repo) -> RepoResult<()> {
     info!("ensuring fetched {:?}", repo);
     if repo.is_cloned() {
-        try!(repo.open_repo());
-        
-        try!(repo.pull_repo());
+        try!(open_cloned_repo(db, repo));
+
+        if repo.sync_state == SyncState::IndexedFiles {
+            try!(repo.transition(db, SyncState::Started));
+        }
+
+        sync_phase(db, repo, SyncState::Fetching, SyncState::Fetched, SyncState::FetchFail, |repo| {
+            try!(repo.pull_repo(db));
 
-        try!(repo.revwalk(db));
-        
-        repo.update_repo_in_db(db)
+            repo.revwalk(db)
+        })
     } else {        
         ensure_cloned(config, db, repo)
     }
 }
 
-fn ensure_indexed(config: &Config, db: &Db, repo: &mut Repo) -> RepoResult<()> {
+/// syncs the repo, and if its clone turns out to be corrupted,
+/// moves it aside and syncs again from a fresh clone
+pub fn ensure_indexed(config: &Config, db: &Db, repo: &mut Repo) -> RepoResult<()> {
+    match resume_indexing(config, db, repo) {
+        Err(RepoError::CorruptedRepo(reason)) => {
+            error!("clone of {} is corrupted: {}. cloning it again", repo.uri, reason);
+
+            try!(repo.transition(db, SyncState::Corrupted));
+
+            resume_indexing(config, db, repo)
+        },
+        result => result
+    }
+}
+
+/// picks up from the phase recorded in the db:
+/// if the last run got as far as indexing commits or files, carry on from there
+/// instead of fetching again
+fn resume_indexing(config: &Config, db: &Db, repo: &mut Repo) -> RepoResult<()> {
     info!("ensuring indexed {:?}", repo);
-    try!(ensure_fetched(&config, db, repo));
 
-    try!(repo.treewalks(db));
-    
     let index = try!(Index::new_for_config(config));
 
-    try!(index.index_repo(db, repo));
+    // opening may find the remote needed repairing, in which case it's fetched again
+    if repo.sync_state.commits_indexed() || repo.sync_state.commits_fetched() {
+        try!(open_cloned_repo(db, repo));
+    }
 
-    Ok(())
+    if repo.sync_state.commits_indexed() {
+        info!("resuming indexing files of {}", repo.uri);
+    } else {
+        if repo.sync_state.commits_fetched() {
+            info!("resuming indexing commits of {}", repo.uri);
+        } else {
+            try!(ensure_fetched(&config, db, repo));
+        }
+
+        try!(sync_phase(db, repo, SyncState::IndexingCommits, SyncState::IndexedCommits, SyncState::IndexCommitsFail, |repo| {
+            try!(repo.treewalks(db));
+
+            index.index_commits(db, repo)
+        }));
+    }
+
+    sync_phase(db, repo, SyncState::IndexingFiles, SyncState::IndexedFiles, SyncState::IndexFilesFail, |repo| {
+        index.index_branches(db, repo)
+    })
 }
 
 /// open db
@@ -94,6 +204,8 @@ pub fn fetch_repo(config: &Config) -> RepoResult<()> {
 
 pub fn index_repo(config: &Config) -> RepoResult<()> {
     let db = try!(open_db(config));
+
+    try!(try!(EsSchema::new_for_config(config)).ensure_index());
     
     let mut repo = try!(Repo::new_for_config(&config));
 
@@ -105,6 +217,100 @@ pub fn index_repo(config: &Config) -> RepoResult<()> {
     Ok(())
 }
 
-pub fn run_sync(_config: &Config) -> RepoResult<()> {
+/// delete the clone so the next sync starts from a fresh one.
+/// the branches' indexed commit ids are kept, so nothing is re-indexed unnecessarily,
+/// unless clear_index is set, in which case the repo's work rows and documents go too
+pub fn remove_clone(config: &Config, db: &Db, repo: &mut Repo, clear_index: bool) -> RepoResult<()> {
+    // the clone goes last, so if clearing the index fails the repo is left as it was
+    if clear_index {
+        let index = try!(Index::new_for_config(config));
+
+        try!(index.delete_repo_documents(&repo.id));
+
+        try!(db.clear_repo_index_state(&repo.id));
+
+        for branch in repo.branches.iter_mut() {
+            branch.indexed_commit = None;
+        }
+    }
+
+    try!(repo.delete_clone());
+
+    repo.transition(db, SyncState::NotCloned)
+}
+
+/// wipes the clone and syncs the repository again from a fresh one.
+/// if zookeeper is configured, this takes the worker lock so a running worker isn't syncing the clone meanwhile
+pub fn recreate(config: &Config) -> RepoResult<()> {
+    let db = try!(open_db(config));
+
+    try!(try!(EsSchema::new_for_config(config)).ensure_index());
+
+    let _zk = match config.zookeeper {
+        Some(_) => {
+            let mut zk = try!(Zk::new_for_config(config));
+
+            try!(zk.acquire_worker_lock(config.sync_config.wait_for_lock));
+
+            Some(zk)
+        },
+        None => None
+    };
+
+    let mut config_repo = try!(Repo::new_for_config(&config));
+
+    let db_repo = try!(config_repo.find_or_create_in_db(&db));
+
+    // the branches as stored, so they keep their indexed commits
+    let mut db_branches = try!(db.find_branches(&db_repo.id));
+
+    for branch in config_repo.branches.iter() {
+        if !db_branches.iter().any(|b| b.name == branch.name) {
+            let db_branch = RepoBranch::new(db_repo.id.clone(), branch.name.clone(), None);
+
+            try!(db.insert_branch(&db_branch));
+
+            db_branches.push(db_branch);
+        }
+    }
+
+    let mut repo = Repo::new_for_db_repo(&db_repo, &db_branches);
+    repo.branch_patterns = config_repo.branch_patterns.clone();
+    repo.tag_patterns = config_repo.tag_patterns.clone();
+    repo.use_credentials(config);
+
+    try!(repo.update_repo_in_db(&db));
+
+    try!(remove_clone(config, &db, &mut repo, config.clear_index));
+
+    try!(ensure_indexed(&config, &db, &mut repo));
+
+    Ok(())
+}
+
+/// creates the elasticsearch index and alias, or upgrades them to the current mapping version
+pub fn es_init(config: &Config) -> RepoResult<()> {
+    let schema = try!(EsSchema::new_for_config(config));
+
+    schema.init()
+}
+
+/// queries the index and prints the matching files and commits
+pub fn search(config: &Config) -> RepoResult<()> {
+    let options = try!(config.search_options.as_ref().ok_or(RepoError::InvalidArgs("no search query".to_string())));
+
+    let index = try!(Index::new_for_config(config));
+
+    let hits = try!(search::search(&index, options));
+
+    search::print_hits(&hits, options);
+
     Ok(())
 }
+
+/// runs the worker loop over all repos in the db until killed
+pub fn run_sync(config: &Config) -> RepoResult<()> {
+    let mut worker = try!(Worker::new(config));
+
+    worker.run()
+}
@@ -1,18 +1,23 @@
 use std::env;
+use std::str::FromStr;
 use clap::{App, SubCommand, ArgMatches};
 use toml::{Table, Parser};
 use std::io::{Read,Result,Error,ErrorKind};
 use std::fs::File;
 use super::result::*;
+use super::credentials::CredentialConfig;
 
 #[derive(Debug,Clone)]
 pub struct Config {
     pub data_dir: String, // where to create database and repo clones
-    pub zookeeper: Option<String>, // e.g. localhost:2181/codelauf
+    pub zookeeper: Option<String>, // e.g. localhost:2181
     pub elasticsearch: Option<String>, // e.g. localhost:9200
     pub index_config: IndexConfig,
     pub sync_config: SyncConfig,
     pub repo_location: Option<RepoLocation>,
+    pub search_options: Option<SearchOptions>,
+    pub credentials: Vec<CredentialConfig>, // for private remotes
+    pub clear_index: bool, // recreate also deletes the repo's documents and indexes it from scratch
 }
 
 impl Config {
@@ -24,6 +29,9 @@ impl Config {
             index_config: IndexConfig::new(),
             sync_config: SyncConfig::new(),
             repo_location: None,
+            search_options: None,
+            credentials: vec![],
+            clear_index: false,
         }
     }
     
@@ -47,34 +55,83 @@ impl Config {
             .get("sync")
             .map(|m| SyncConfig::new_from_table(m.as_table().unwrap()) )
             .unwrap_or(cfg.sync_config);
+        cfg.credentials = table
+            .get("credentials")
+            .map(|m| m.as_slice().unwrap().iter().map(|c| CredentialConfig::new_from_table(c.as_table().unwrap())).collect())
+            .unwrap_or(cfg.credentials);
         cfg
     }
+
+    /// split the elasticsearch host:port setting, defaulting the port to 9200
+    pub fn get_elasticsearch(&self) -> RepoResult<(String, u32)> {
+        let es_url_str: &str = try!(self.elasticsearch.as_ref().ok_or(RepoError::NoElasticSearch));
+
+        let mut es_url_parts = es_url_str.split(":");
+        
+        let es_host = try!(es_url_parts.next().ok_or(RepoError::NoElasticSearch));
+        let es_port = try!(es_url_parts.next().map(|s| s.parse::<u32>()).unwrap_or(Ok(9200)));
+
+        Ok((es_host.to_owned(), es_port))
+    }
 }
 
 #[derive(Debug,Clone)]
-pub struct IndexConfig;
+pub struct IndexConfig {
+    pub bulk_batch_size: usize, // max number of documents sent in one _bulk request
+    pub bulk_flush_interval_secs: u64, // send a partial batch if it's been waiting this long
+}
 
 impl IndexConfig {
     pub fn new() -> IndexConfig {
-        IndexConfig
+        IndexConfig {
+            bulk_batch_size: 500,
+            bulk_flush_interval_secs: 10,
+        }
     }
     
-    pub fn new_from_table(_table: &Table) -> IndexConfig {
-        let cfg = Self::new();
+    pub fn new_from_table(table: &Table) -> IndexConfig {
+        let mut cfg = Self::new();
+        cfg.bulk_batch_size = table
+            .get("bulk_batch_size")
+            .map(|m| m.as_integer().unwrap() as usize)
+            .unwrap_or(cfg.bulk_batch_size);
+        cfg.bulk_flush_interval_secs = table
+            .get("bulk_flush_interval_secs")
+            .map(|m| m.as_integer().unwrap() as u64)
+            .unwrap_or(cfg.bulk_flush_interval_secs);
         cfg
     }
 }
 
 #[derive(Debug,Clone)]
-pub struct SyncConfig;
+pub struct SyncConfig {
+    pub interval_secs: u64, // how long the worker sleeps between sync runs
+    pub wait_for_lock: bool, // block until the active worker goes away, or exit
+    pub http_listen: Option<String>, // e.g. 127.0.0.1:8080 to serve the worker management api
+}
 
 impl SyncConfig {
     pub fn new() -> SyncConfig {
-        SyncConfig
+        SyncConfig {
+            interval_secs: 300,
+            wait_for_lock: true,
+            http_listen: None,
+        }
     }
 
-    pub fn new_from_table(_table: &Table) -> SyncConfig {
-        let cfg = Self::new();
+    pub fn new_from_table(table: &Table) -> SyncConfig {
+        let mut cfg = Self::new();
+        cfg.interval_secs = table
+            .get("interval_secs")
+            .map(|m| m.as_integer().unwrap() as u64)
+            .unwrap_or(cfg.interval_secs);
+        cfg.wait_for_lock = table
+            .get("wait_for_lock")
+            .map(|m| m.as_bool().unwrap())
+            .unwrap_or(cfg.wait_for_lock);
+        cfg.http_listen = table
+            .get("http_listen")
+            .map(|m| m.as_str().unwrap().to_string());
         cfg
     }
 }
@@ -83,6 +140,7 @@ impl SyncConfig {
 pub struct RepoLocation {
     pub remote: Option<String>,
     pub branches: Vec<String>,
+    pub tags: Vec<String>, // globs of tags to index as well, e.g. v*
     pub dir: Option<String>,
 }
 
@@ -91,6 +149,7 @@ impl RepoLocation {
         RepoLocation {
             remote: None,
             branches: vec![],
+            tags: vec![],
             dir: None,
         }
     }
@@ -116,6 +175,15 @@ impl RepoLocation {
                     repo_loc.branches.push("master".to_string());
                 }
             }
+
+            match args.values_of("TAGS") {
+                Some(tags) => {
+                    for tag in tags {
+                        repo_loc.tags.push(tag.to_string());
+                    }
+                },
+                None => {}
+            }
             
             repo_loc.dir = get_config_str(args, "REPO_DIR")
                 .or(repo_loc.dir);
@@ -127,6 +195,60 @@ impl RepoLocation {
     }
 }
 
+#[derive(Debug,Clone)]
+pub struct SearchOptions {
+    pub query: String,
+    pub remote: Option<String>,
+    pub branch: Option<String>, // only applies to files
+    pub path: Option<String>, // glob matched against files and the files commits touched
+    pub author: Option<String>, // name or email, only applies to commits
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub doc_type: Option<String>, // file or commit
+    pub symbol: bool, // find definitions named query instead of searching text
+    pub prefix: bool, // with symbol, find definitions whose names start with query
+    pub pickaxe: bool, // find commits that added or removed query, like git log -S
+    pub limit: usize,
+}
+
+impl SearchOptions {
+    pub fn new(query: String) -> SearchOptions {
+        SearchOptions {
+            query: query,
+            remote: None,
+            branch: None,
+            path: None,
+            author: None,
+            since: None,
+            until: None,
+            doc_type: None,
+            symbol: false,
+            prefix: false,
+            pickaxe: false,
+            limit: 20,
+        }
+    }
+
+    pub fn new_from_args<'a,'b>(args: &ArgMatches<'a,'b>) -> RepoResult<SearchOptions> {
+        let mut opts = SearchOptions::new(get_config_str(args, "QUERY").unwrap_or(String::new()));
+
+        opts.remote = get_config_str(args, "REMOTE");
+        opts.branch = get_config_str(args, "BRANCH");
+        opts.path = get_config_str(args, "PATH");
+        opts.author = get_config_str(args, "AUTHOR");
+        opts.since = get_config_str(args, "SINCE");
+        opts.until = get_config_str(args, "UNTIL");
+        opts.doc_type = get_config_str(args, "TYPE");
+        opts.symbol = args.is_present("symbol");
+        opts.prefix = args.is_present("prefix");
+        opts.pickaxe = args.is_present("pickaxe");
+        opts.limit = try!(get_config_num(args, "LIMIT"))
+            .unwrap_or(opts.limit);
+
+        Ok(opts)
+    }
+}
+
 pub fn parse_args<'a,'b>() -> ArgMatches<'a,'b> {
     App::new("codelauf")
         .version("1.0")
@@ -134,30 +256,64 @@ pub fn parse_args<'a,'b>() -> ArgMatches<'a,'b> {
         .about("Codelauf indexes git repositories for search")
         .args_from_usage(
             "-c --config=[CONFIG] 'Sets a custom config file'
-            -z --zookeeper=[ZOOKEEPER] 'Zookeeper host:port[/dir] (env var ZOOKEEPER)'
+            -z --zookeeper=[ZOOKEEPER] 'Zookeeper host:port (env var ZOOKEEPER)'
             -e --elasticsearch=[ELASTICSEARCH] 'Elasticsearch host:port (env var ELASTICSEARCH)'
             -d --data-dir=[DATA_DIR] 'Data directory'")
         .subcommand(SubCommand::with_name("init")
                     .about("creates the local database and exits")
                     .args_from_usage("")
                     )
+        .subcommand(SubCommand::with_name("es-init")
+                    .about("creates or upgrades the elasticsearch index and exits")
+                    .args_from_usage("")
+                    )
         .subcommand(SubCommand::with_name("index")
                     .about("indexes a single repository and exits")
                     .args_from_usage(
                         "-r --remote=[REMOTE] 'Repository remote url (required if not already cloned)'
-                        -b --branch=[BRANCH] 'Branch (default master)'
+                        -b --branch=[BRANCH] 'Branch, glob of remote branches e.g. release/*, or all (default master)'
+                        -t --tags=[TAGS] 'Also index tags matching this glob, e.g. v*'
                         -R --repo-dir=[REPO_DIR] 'Repo dir to use for repo (clones if it does not exist)'")
                     )
         .subcommand(SubCommand::with_name("fetch")
                     .about("clones or fetches a repository and exits")
                     .args_from_usage(
                         "-r --remote=[REMOTE] 'Repository remote url (required if not already cloned)'
-                        -b --branch=[BRANCH] 'Branch (default master)'
+                        -b --branch=[BRANCH] 'Branch, glob of remote branches e.g. release/*, or all (default master)'
+                        -t --tags=[TAGS] 'Also index tags matching this glob, e.g. v*'
                         -R --repo-dir=[REPO_DIR] 'Repo dir to use for repo (clones if it does not exist)'")
                     )
+        .subcommand(SubCommand::with_name("recreate")
+                    .about("deletes a repository's clone, then clones and indexes it again and exits")
+                    .args_from_usage(
+                        "-r --remote=[REMOTE] 'Repository remote url (required if not already cloned)'
+                        -b --branch=[BRANCH] 'Branch, glob of remote branches e.g. release/*, or all (default master)'
+                        -t --tags=[TAGS] 'Also index tags matching this glob, e.g. v*'
+                        -R --repo-dir=[REPO_DIR] 'Repo dir to use for repo (clones if it does not exist)'
+                        --clear-index 'Also delete its documents from the index and index everything again'")
+                    )
+        .subcommand(SubCommand::with_name("search")
+                    .about("searches the index and prints matching files and commits")
+                    .args_from_usage(
+                        "<QUERY> 'Text to search for'
+                        -r --remote=[REMOTE] 'Only search this repository remote url'
+                        -b --branch=[BRANCH] 'Only show files on this branch'
+                        -p --path=[PATH] 'Only show files matching this glob, and commits touching them, e.g. src/**/*.rs'
+                        -a --author=[AUTHOR] 'Only show commits by this author name or email'
+                        --since=[SINCE] 'Only show commits and files changed on or after this date'
+                        --until=[UNTIL] 'Only show commits and files changed on or before this date'
+                        -t --type=[TYPE] 'Only show file or commit results'
+                        -s --symbol 'Find where things named QUERY are defined instead'
+                        --prefix 'With --symbol, find definitions with names starting with QUERY'
+                        -S --pickaxe 'Find commits that added or removed QUERY (at least 3 characters), newest first. Not with --branch'
+                        -n --limit=[LIMIT] 'Maximum number of results (default 20)'")
+                    )
         .subcommand(SubCommand::with_name("sync")
                     .about("starts the worker process to mirror and index repos")
-                    .args_from_usage("")
+                    .args_from_usage(
+                        "-i --interval=[INTERVAL] 'Seconds to wait between sync runs (default 300)'
+                        --no-wait 'Exit instead of waiting if another worker is active'
+                        -l --listen=[LISTEN] 'Serve the worker api on this host:port'")
                     )
         .get_matches()
 }
@@ -190,7 +346,7 @@ pub fn get_env(name: &str) -> Option<String> {
     }
 }
 
-pub fn apply_config<'a,'b>(cfg: Config, args: &ArgMatches<'a,'b>) -> Config {
+pub fn apply_config<'a,'b>(cfg: Config, args: &ArgMatches<'a,'b>) -> RepoResult<Config> {
     let mut cfg = cfg;
     
     cfg.zookeeper = get_config_str_env(args, "ZOOKEEPER", "ZOOKEEPER")
@@ -209,12 +365,26 @@ pub fn apply_config<'a,'b>(cfg: Config, args: &ArgMatches<'a,'b>) -> Config {
         ("fetch", Some(fetchargs)) => {
             cfg.repo_location = RepoLocation::new_from_args(&fetchargs);
         },
-        ("sync", Some(_syncargs)) => {
+        ("recreate", Some(recreateargs)) => {
+            cfg.repo_location = RepoLocation::new_from_args(&recreateargs);
+            cfg.clear_index = recreateargs.is_present("clear-index");
+        },
+        ("search", Some(searchargs)) => {
+            cfg.search_options = Some(try!(SearchOptions::new_from_args(&searchargs)));
+        },
+        ("sync", Some(syncargs)) => {
+            cfg.sync_config.interval_secs = try!(get_config_num(&syncargs, "INTERVAL"))
+                .unwrap_or(cfg.sync_config.interval_secs);
+            if syncargs.is_present("no-wait") {
+                cfg.sync_config.wait_for_lock = false;
+            }
+            cfg.sync_config.http_listen = get_config_str(&syncargs, "LISTEN")
+                .or(cfg.sync_config.http_listen);
         },
         _ => {}
     }
     
-    cfg
+    Ok(cfg)
 }
 
 pub fn get_config_str<'a,'b>(args: &ArgMatches<'a,'b>, key: &str) -> Option<String> {
@@ -222,19 +392,29 @@ pub fn get_config_str<'a,'b>(args: &ArgMatches<'a,'b>, key: &str) -> Option<Stri
         .map(|s| s.to_string())
 }
 
+/// a numeric argument, which is an error rather than ignored if it doesn't parse
+pub fn get_config_num<'a,'b,T: FromStr>(args: &ArgMatches<'a,'b>, key: &str) -> RepoResult<Option<T>> {
+    match args.value_of(key) {
+        Some(s) => s.parse::<T>()
+            .map(|n| Some(n))
+            .map_err(|_| RepoError::InvalidArgs(format!("{} must be a number, not {}", key, s))),
+        None => Ok(None)
+    }
+}
+
 pub fn get_config_str_env<'a,'b>(args: &ArgMatches<'a,'b>, key: &str, env_key: &str) -> Option<String> {
     args.value_of(key)
         .map(|s| s.to_string())
         .or(get_env(env_key))
 }
 
-pub fn get_config<'a,'b>(args: &ArgMatches<'a,'b>) -> Result<Config> {
+pub fn get_config<'a,'b>(args: &ArgMatches<'a,'b>) -> RepoResult<Config> {
     let maybe_config = read_config(get_config_str(args, "CONFIG"));
 
-    maybe_config.map_err(|err| {
+    let cfg = try!(maybe_config.map_err(|err| {
         error!("error reading config file: {:?}", err);
         err
-    }).map(|cfg| {
-        apply_config(cfg, args)
-    })
+    }));
+
+    apply_config(cfg, args)
 }
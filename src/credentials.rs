@@ -0,0 +1,343 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use toml::Table;
+use git2;
+use config::get_env;
+use result::*;
+
+/// where to read a password, token or passphrase from.
+/// secrets can't be given inline, so the config file can be shared
+#[derive(Debug,Clone,PartialEq)]
+pub enum Secret {
+    File(PathBuf),
+    Env(String),
+}
+
+impl Secret {
+    /// from the <name>_file or <name>_env key of a table
+    pub fn new_from_table(table: &Table, name: &str) -> Option<Secret> {
+        let file = table.get(&format!("{}_file", name)).and_then(|m| m.as_str()).map(|s| Secret::File(PathBuf::from(s)));
+        let env = table.get(&format!("{}_env", name)).and_then(|m| m.as_str()).map(|s| Secret::Env(s.to_string()));
+
+        file.or(env)
+    }
+
+    /// the secret, without the trailing newline a file usually ends with
+    pub fn read(&self) -> RepoResult<String> {
+        match *self {
+            Secret::File(ref path) => {
+                let mut f = try!(File::open(path));
+
+                let mut s = String::new();
+                try!(f.read_to_string(&mut s));
+
+                Ok(s.trim_right_matches(|c| c == '\n' || c == '\r').to_string())
+            },
+            Secret::Env(ref name) => {
+                get_env(name).ok_or(RepoError::CredentialsError(format!("environment variable {} not set", name)))
+            }
+        }
+    }
+}
+
+/// how to authenticate with the remotes of one repo, or all the repos on a host.
+/// a [[credentials]] table in the config file
+#[derive(Debug,Clone)]
+pub struct CredentialConfig {
+    pub remote: Option<String>, // exact remote url this applies to
+    pub host: Option<String>, // or every remote on this host
+    pub username: Option<String>, // defaults to the one in the url, or git for ssh
+    pub ssh_key: Option<PathBuf>, // private key file
+    pub ssh_public_key: Option<PathBuf>,
+    pub ssh_passphrase: Option<Secret>,
+    pub ssh_agent: bool, // use keys from ssh-agent if no key file is given
+    pub password: Option<Secret>, // https password or token
+    pub credential_helper: bool, // ask git's configured credential helper if no password is given
+}
+
+impl CredentialConfig {
+    pub fn new() -> CredentialConfig {
+        CredentialConfig {
+            remote: None,
+            host: None,
+            username: None,
+            ssh_key: None,
+            ssh_public_key: None,
+            ssh_passphrase: None,
+            ssh_agent: false,
+            password: None,
+            credential_helper: false,
+        }
+    }
+
+    pub fn new_from_table(table: &Table) -> CredentialConfig {
+        let mut cfg = Self::new();
+        cfg.remote = table
+            .get("remote")
+            .map(|m| m.as_str().unwrap().to_string());
+        cfg.host = table
+            .get("host")
+            .map(|m| m.as_str().unwrap().to_string());
+        cfg.username = table
+            .get("username")
+            .map(|m| m.as_str().unwrap().to_string());
+        cfg.ssh_key = table
+            .get("ssh_key")
+            .map(|m| PathBuf::from(m.as_str().unwrap()));
+        cfg.ssh_public_key = table
+            .get("ssh_public_key")
+            .map(|m| PathBuf::from(m.as_str().unwrap()));
+        cfg.ssh_passphrase = Secret::new_from_table(table, "ssh_passphrase");
+        cfg.ssh_agent = table
+            .get("ssh_agent")
+            .map(|m| m.as_bool().unwrap())
+            .unwrap_or(cfg.ssh_agent);
+        cfg.password = Secret::new_from_table(table, "password");
+        cfg.credential_helper = table
+            .get("credential_helper")
+            .map(|m| m.as_bool().unwrap())
+            .unwrap_or(cfg.credential_helper);
+        cfg
+    }
+
+    /// credentials for the exact remote are preferred over ones for its host
+    pub fn find_for_remote<'a>(configs: &'a Vec<CredentialConfig>, remote: &str) -> Option<&'a CredentialConfig> {
+        let host = remote_host(remote);
+
+        configs.iter().find(|c| c.remote.as_ref().map(|r| r == remote).unwrap_or(false))
+            .or_else(|| configs.iter().find(|c| c.remote.is_none() && c.host.is_some() && c.host == host))
+    }
+
+    /// answers libgit2's request for credentials.
+    /// it asks again if they're rejected, so each type is only given once
+    /// rather than retrying the same ones forever. tried holds the types given so far
+    pub fn git_credentials(&self, url: &str, username_from_url: Option<&str>, allowed: git2::CredentialType, tried: &mut git2::CredentialType) -> Result<git2::Cred, git2::Error> {
+        let untried = allowed - *tried;
+
+        let username = self.username.as_ref().map(|u| &u[..]).or(username_from_url);
+
+        // ssh remotes without a user in the url ask for one before asking for a key
+        if untried.contains(git2::USERNAME) {
+            *tried = *tried | git2::USERNAME;
+
+            return git2::Cred::username(username.unwrap_or("git"));
+        }
+
+        if untried.contains(git2::SSH_KEY) {
+            let username = username.unwrap_or("git");
+
+            match self.ssh_key {
+                Some(ref key) => {
+                    let passphrase = try!(read_secret(&self.ssh_passphrase));
+
+                    info!("using ssh key {:?} for {}", key, url);
+
+                    *tried = *tried | git2::SSH_KEY;
+
+                    return git2::Cred::ssh_key(username, self.ssh_public_key.as_ref().map(|p| p.as_path()), key, passphrase.as_ref().map(|p| &p[..]));
+                },
+                None => {}
+            }
+
+            if self.ssh_agent {
+                info!("using ssh-agent for {}", url);
+
+                *tried = *tried | git2::SSH_KEY;
+
+                return git2::Cred::ssh_key_from_agent(username);
+            }
+        }
+
+        if untried.contains(git2::USER_PASS_PLAINTEXT) {
+            match try!(read_secret(&self.password)) {
+                Some(password) => {
+                    info!("using password for {}", url);
+
+                    *tried = *tried | git2::USER_PASS_PLAINTEXT;
+
+                    return git2::Cred::userpass_plaintext(username.unwrap_or(""), &password);
+                },
+                None => {}
+            }
+
+            if self.credential_helper {
+                info!("using git credential helper for {}", url);
+
+                let git_config = try!(git2::Config::open_default());
+
+                *tried = *tried | git2::USER_PASS_PLAINTEXT;
+
+                return git2::Cred::credential_helper(&git_config, url, username);
+            }
+        }
+
+        if allowed.intersects(*tried) {
+            Err(git2::Error::from_str(&format!("credentials for {} were rejected", url)))
+        } else {
+            Err(git2::Error::from_str(&format!("no usable credentials configured for {}", url)))
+        }
+    }
+}
+
+fn read_secret(secret: &Option<Secret>) -> Result<Option<String>, git2::Error> {
+    match *secret {
+        Some(ref secret) => secret.read().map(|s| Some(s)).map_err(|e| git2::Error::from_str(&format!("{:?}", e))),
+        None => Ok(None)
+    }
+}
+
+/// the host of an https://, ssh:// or scp style user@host:path remote.
+/// local remotes don't have one
+pub fn remote_host(remote: &str) -> Option<String> {
+    let rest = match remote.find("://") {
+        Some(i) if &remote[..i] == "file" => return None,
+        Some(i) => &remote[i + 3..],
+        None if remote.contains(':') => remote,
+        None => return None,
+    };
+
+    let authority = rest.split('/').next().unwrap_or("");
+
+    let host_port = match authority.rfind('@') {
+        Some(i) => &authority[i + 1..],
+        None => authority,
+    };
+
+    let host = host_port.split(':').next().unwrap_or("");
+
+    if host.is_empty() {
+        None
+    } else {
+        Some(host.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs::{self,File};
+    use std::io::Write;
+    use toml::Parser;
+    use git2;
+    use hyper;
+    use hyper::server::{Request,Response};
+    use hyper::status::StatusCode;
+
+    #[test]
+    pub fn test_remote_host() {
+        assert_eq!(remote_host("https://github.com/cmsd2/codelauf.git"), Some("github.com".to_string()));
+        assert_eq!(remote_host("ssh://git@example.com:2222/repo.git"), Some("example.com".to_string()));
+        assert_eq!(remote_host("git@github.com:cmsd2/codelauf.git"), Some("github.com".to_string()));
+        assert_eq!(remote_host("file:///srv/git/repo.git"), None);
+        assert_eq!(remote_host("/srv/git/repo.git"), None);
+    }
+
+    #[test]
+    pub fn test_find_for_remote() {
+        let toml = r#"
+            [[credentials]]
+            host = "github.com"
+            username = "bot"
+            password_env = "CODELAUF_TEST_TOKEN"
+
+            [[credentials]]
+            remote = "https://github.com/cmsd2/private.git"
+            ssh_agent = true
+        "#;
+
+        let table = Parser::new(toml).parse().unwrap();
+        let configs: Vec<CredentialConfig> = table.get("credentials").unwrap().as_slice().unwrap().iter()
+            .map(|c| CredentialConfig::new_from_table(c.as_table().unwrap()))
+            .collect();
+
+        let host_creds = CredentialConfig::find_for_remote(&configs, "https://github.com/cmsd2/codelauf.git").unwrap();
+        assert_eq!(host_creds.username, Some("bot".to_string()));
+        assert_eq!(host_creds.password, Some(Secret::Env("CODELAUF_TEST_TOKEN".to_string())));
+
+        let remote_creds = CredentialConfig::find_for_remote(&configs, "https://github.com/cmsd2/private.git").unwrap();
+        assert!(remote_creds.ssh_agent);
+
+        assert!(CredentialConfig::find_for_remote(&configs, "https://example.com/repo.git").is_none());
+    }
+
+    #[test]
+    pub fn test_read_secret() {
+        let path = env::temp_dir().join("codelauf_test_secret");
+        File::create(&path).unwrap().write_all(b"s3cret\n").unwrap();
+
+        assert_eq!(Secret::File(path).read().unwrap(), "s3cret");
+
+        env::set_var("CODELAUF_TEST_SECRET", "t0ken");
+        assert_eq!(Secret::Env("CODELAUF_TEST_SECRET".to_string()).read().unwrap(), "t0ken");
+
+        assert!(Secret::Env("CODELAUF_TEST_UNSET_SECRET".to_string()).read().is_err());
+    }
+
+    #[test]
+    pub fn test_git_credentials() {
+        env::set_var("CODELAUF_TEST_PASSWORD", "hunter2");
+
+        let mut creds = CredentialConfig::new();
+        creds.password = Some(Secret::Env("CODELAUF_TEST_PASSWORD".to_string()));
+
+        let mut tried = git2::CredentialType::empty();
+
+        // ssh://host/repo.git asks for a username, then a key
+        assert!(creds.git_credentials("ssh://example.com/repo.git", None, git2::USERNAME, &mut tried).is_ok());
+        assert!(creds.git_credentials("ssh://example.com/repo.git", None, git2::SSH_KEY, &mut tried).is_err());
+
+        assert!(creds.git_credentials("https://example.com/repo.git", Some("bot"), git2::USER_PASS_PLAINTEXT, &mut tried).is_ok());
+        assert_eq!(tried, git2::USERNAME | git2::USER_PASS_PLAINTEXT);
+
+        // the password was rejected, so it isn't given again
+        assert!(creds.git_credentials("https://example.com/repo.git", Some("bot"), git2::USER_PASS_PLAINTEXT, &mut tried).is_err());
+    }
+
+    #[test]
+    pub fn test_git_credentials_rejected_by_remote() {
+        env::set_var("CODELAUF_TEST_REJECTED_PASSWORD", "hunter2");
+
+        let mut creds = CredentialConfig::new();
+        creds.password = Some(Secret::Env("CODELAUF_TEST_REJECTED_PASSWORD".to_string()));
+
+        // a remote that turns down every password
+        let server = hyper::Server::http("127.0.0.1:0").unwrap();
+        let mut listening = server.handle(|_req: Request, mut res: Response| {
+            *res.status_mut() = StatusCode::Unauthorized;
+            res.headers_mut().set_raw("WWW-Authenticate", vec![b"Basic realm=\"codelauf\"".to_vec()]);
+            res.send(b"").unwrap();
+        }).unwrap();
+
+        let path = env::temp_dir().join("codelauf_test_rejected_clone");
+        let _ = fs::remove_dir_all(&path);
+
+        let mut tried = git2::CredentialType::empty();
+        let mut calls = 0;
+
+        let result = {
+            let mut grcs = git2::RemoteCallbacks::new();
+            grcs.credentials(|url, username_from_url, allowed| {
+                calls += 1;
+                creds.git_credentials(url, username_from_url, allowed, &mut tried)
+            });
+
+            let mut fo = git2::FetchOptions::new();
+            fo.remote_callbacks(grcs);
+
+            let mut rb = git2::build::RepoBuilder::new();
+            rb.bare(true);
+            rb.fetch_options(fo);
+
+            rb.clone(&format!("http://{}/repo.git", listening.socket), &path).map(|_| ())
+        };
+
+        listening.close().unwrap();
+        let _ = fs::remove_dir_all(&path);
+
+        assert!(result.is_err());
+        assert_eq!(calls, 2);
+        assert_eq!(tried, git2::USER_PASS_PLAINTEXT);
+    }
+}
@@ -41,9 +41,13 @@ impl Db {
         migrator.register(Box::new(CreateBranchesTable));
         migrator.register(Box::new(CreateCommitsTable));
         migrator.register(Box::new(CreateFilesTable));
+        migrator.register(Box::new(AddFilesBlobIds));
+        migrator.register(Box::new(AddRepositoriesTagPatterns));
+        migrator.register(Box::new(AddRepositoriesBranchPatterns));
+        migrator.register(Box::new(AddFilesSymbolCounts));
 
-        migrator.up(4);
-        assert_eq!(migrator.current_version(), Some(4));
+        migrator.up(8);
+        assert_eq!(migrator.current_version(), Some(8));
     }
 
     pub fn find_repo_by_remote(&self, remote: &String) -> RepoResult<Option<Repository>> {
@@ -59,13 +63,35 @@ impl Db {
         }
     }
 
+    pub fn find_repos(&self) -> RepoResult<Vec<Repository>> {
+        let mut stmt = try!(self.conn.prepare("SELECT * FROM repositories").map_err(|e| RepoError::SqlError(e)));
+
+        let rows = try!(stmt.query(&[]));
+
+        let mut result = vec![];
+
+        for row_result in rows {
+            let row = try!(row_result);
+
+            let repo = try!(Repository::new_from_sql_row(&row));
+
+            result.push(repo);
+        }
+
+        Ok(result)
+    }
+
     pub fn find_repo(&self, id: &str) -> RepoResult<Option<Repository>> {
         let mut stmt = try!(self.conn.prepare("SELECT * FROM repositories WHERE id = ?").map_err(|e| RepoError::SqlError(e)));
         let mut rows = try!(stmt.query(&[&id]));
 
-        let row0 = try!(rows.next().unwrap());
-
-        Repository::new_from_sql_row(&row0).map(|r| Some(r))
+        match rows.next() {
+            None => Ok(None),
+            Some(row_result) => {
+                let row = try!(row_result);
+                Repository::new_from_sql_row(&row).map(|r| Some(r))
+            }
+        }
     }
 
     pub fn update_repo(&self, repo: &Repository) -> RepoResult<()> {
@@ -74,13 +100,17 @@ impl Db {
         let mut stmt = try!(self.conn.prepare("UPDATE repositories SET \
                                                path=?, sync_state=?, \
                                                fetched_datetime=?, \
-                                               indexed_datetime=? \
+                                               indexed_datetime=?, \
+                                               tag_patterns=?, \
+                                               branch_patterns=? \
                                                WHERE id=?").map_err(|e| RepoError::SqlError(e)));
         try!(stmt.execute(&[
             &path,
             &repo.sync_state.to_string(),
             &repo.fetched_datetime,
             &repo.indexed_datetime,
+            &repo.tag_patterns.join("\n"),
+            &repo.branch_patterns.join("\n"),
             &repo.id]));
         Ok(())
     }
@@ -88,7 +118,7 @@ impl Db {
     pub fn insert_repo(&self, repo: &Repository) -> RepoResult<()> {
         let path = try!(path_to_bytes_vec(&repo.path));
         
-        let mut stmt = try!(self.conn.prepare("INSERT INTO repositories VALUES (?,?,?,?,?,?,?)").map_err(|e| RepoError::SqlError(e)));
+        let mut stmt = try!(self.conn.prepare("INSERT INTO repositories VALUES (?,?,?,?,?,?,?,?,?)").map_err(|e| RepoError::SqlError(e)));
         try!(stmt.execute(&[
             &repo.id,
             &repo.uri,
@@ -96,7 +126,22 @@ impl Db {
             &repo.sync_state.to_string(),
             &repo.added_datetime,
             &repo.fetched_datetime,
-            &repo.indexed_datetime]));
+            &repo.indexed_datetime,
+            &repo.tag_patterns.join("\n"),
+            &repo.branch_patterns.join("\n")]));
+        Ok(())
+    }
+
+    /// delete the repo and all its rows in the branches, commits and files tables
+    pub fn delete_repo(&self, repo_id: &str) -> RepoResult<()> {
+        for table in ["files", "commits", "branches"].iter() {
+            let mut stmt = try!(self.conn.prepare(&format!("DELETE FROM {} WHERE repo_id = ?", table)));
+            try!(stmt.execute(&[&repo_id]));
+        }
+
+        let mut del_repo_stmt = try!(self.conn.prepare("DELETE FROM repositories WHERE id = ?"));
+        try!(del_repo_stmt.execute(&[&repo_id]));
+
         Ok(())
     }
 
@@ -104,20 +149,62 @@ impl Db {
         let mut stmt = try!(self.conn.prepare("SELECT * FROM branches WHERE repo_id = ? AND name = ?").map_err(|e| RepoError::SqlError(e)));
         let mut rows = try!(stmt.query(&[&repo_id, &name]));
 
-        let row0 = try!(rows.next().unwrap());
+        match rows.next() {
+            None => Ok(None),
+            Some(row_result) => {
+                let row = try!(row_result);
+                RepoBranch::new_from_sql_row(&row).map(|r| Some(r))
+            }
+        }
+    }
+
+    pub fn find_branches(&self, repo_id: &str) -> RepoResult<Vec<RepoBranch>> {
+        let mut stmt = try!(self.conn.prepare("SELECT * FROM branches WHERE repo_id = ?").map_err(|e| RepoError::SqlError(e)));
+
+        let rows = try!(stmt.query(&[&repo_id]));
 
-        RepoBranch::new_from_sql_row(&row0).map(|r| Some(r))
+        let mut result = vec![];
+
+        for row_result in rows {
+            let row = try!(row_result);
+
+            let branch = try!(RepoBranch::new_from_sql_row(&row));
+
+            result.push(branch);
+        }
+
+        Ok(result)
     }
 
     pub fn mark_branch_as_indexed(&self, repo_id: &str, branch: &str, commit_id: &str) -> RepoResult<()> {
         let mut stmt = try!(self.conn.prepare("UPDATE branches SET \
                                                indexed_commit_id=? \
                                                WHERE repo_id=? AND name=?").map_err(|e| RepoError::SqlError(e)));
-        try!(stmt.execute(&[&repo_id, &branch, &commit_id]));
+        try!(stmt.execute(&[&commit_id, &repo_id, &branch]));
         
         Ok(())
     }
 
+    pub fn clear_branch_indexed_commit(&self, repo_id: &str, branch: &str) -> RepoResult<()> {
+        let mut stmt = try!(self.conn.prepare("UPDATE branches SET \
+                                               indexed_commit_id=NULL \
+                                               WHERE repo_id=? AND name=?").map_err(|e| RepoError::SqlError(e)));
+        try!(stmt.execute(&[&repo_id, &branch]));
+
+        Ok(())
+    }
+
+    /// delete the branch and its rows in the files table
+    pub fn delete_branch(&self, repo_id: &str, name: &str) -> RepoResult<()> {
+        let mut del_files_stmt = try!(self.conn.prepare("DELETE FROM files WHERE repo_id = ? AND branch = ?"));
+        try!(del_files_stmt.execute(&[&repo_id, &name]));
+
+        let mut del_branch_stmt = try!(self.conn.prepare("DELETE FROM branches WHERE repo_id = ? AND name = ?"));
+        try!(del_branch_stmt.execute(&[&repo_id, &name]));
+
+        Ok(())
+    }
+
     pub fn insert_branch(&self, branch: &RepoBranch) -> RepoResult<()> {
         let mut stmt = try!(self.conn.prepare("INSERT INTO branches VALUES (?,?,?)").map_err(|e| RepoError::SqlError(e)));
         try!(stmt.execute(&[
@@ -156,6 +243,53 @@ impl Db {
         Ok(result)
     }
 
+    /// record that a commit should be removed from the index, whether or not it's been indexed yet
+    pub fn mark_commit_as_orphaned(&self, repo_id: &str, commit_id: &str) -> RepoResult<()> {
+        let mut stmt = try!(self.conn.prepare("INSERT OR REPLACE INTO commits VALUES (?,?,?)").map_err(|e| RepoError::SqlError(e)));
+
+        try!(stmt.execute(&[
+            &commit_id,
+            &repo_id,
+            &CommitState::Orphaned.to_string()]));
+
+        Ok(())
+    }
+
+    pub fn find_orphaned_commits(&self, repo_id: &str) -> RepoResult<Vec<String>> {
+        let mut stmt = try!(self.conn.prepare("SELECT * FROM commits WHERE state = 'Orphaned' AND repo_id = ?").map_err(|e| RepoError::SqlError(e)));
+        
+        let rows = try!(stmt.query(&[&repo_id]));
+
+        let mut result = vec![];
+
+        for row_result in rows {
+            let row = try!(row_result);
+            
+            let commit_id: String = row.get(0);
+
+            result.push(commit_id);
+        }
+        
+        Ok(result)
+    }
+
+    pub fn delete_commit(&self, repo_id: &str, commit_id: &str) -> RepoResult<()> {
+        let mut stmt = try!(self.conn.prepare("DELETE FROM commits WHERE id = ? AND repo_id = ?"));
+
+        try!(stmt.execute(&[&commit_id, &repo_id]));
+
+        Ok(())
+    }
+
+    pub fn count_commits(&self, repo_id: &str) -> RepoResult<usize> {
+        let count = try!(self.conn.query_row("SELECT COUNT(*) FROM commits WHERE state != 'Orphaned' AND repo_id = ?", &[&repo_id], |row| {
+            let count: i64 = row.get(0);
+            count
+        }));
+
+        Ok(count as usize)
+    }
+
     pub fn mark_commit_as_indexed(&self, repo_id: &str, commit_id: &str) -> RepoResult<()> {
         let mut stmt = try!(self.conn.prepare("UPDATE commits SET \
                                                state = 'Indexed' \
@@ -166,22 +300,86 @@ impl Db {
         Ok(())
     }
 
-    /// create row in files table, or update changed_commit_id if it exists.
-    /// indexed_commit_id will be set to null.
-    pub fn upsert_file(&self, repo_id: &str, branch: &str, path: &Path, changed_commit_id: Option<&str>) -> RepoResult<()> {
-        let mut stmt = try!(self.conn.prepare("INSERT OR REPLACE INTO files \
-                                               (repo_id, branch, path, changed_commit_id) \
-                                               VALUES (?,?,?,?)"));
+    /// create row in files table, or update changed_commit_id and blob_id if it exists.
+    /// the indexed columns are kept, so the previously indexed version can be removed from the index.
+    /// a null changed_commit_id means the file has been deleted from the branch.
+    pub fn upsert_file(&self, repo_id: &str, branch: &str, path: &Path, changed_commit_id: Option<&str>, blob_id: Option<&str>) -> RepoResult<()> {
+        let path_bytes = try!(path_to_bytes(path));
+
+        let mut update_stmt = try!(self.conn.prepare("UPDATE files SET \
+                                                      changed_commit_id = ?, blob_id = ? \
+                                                      WHERE repo_id = ? AND branch = ? AND path = ?"));
+
+        let updated = try!(update_stmt.execute(&[&changed_commit_id, &blob_id, &repo_id, &branch, &path_bytes]));
+
+        if updated == 0 {
+            let mut insert_stmt = try!(self.conn.prepare("INSERT INTO files \
+                                                          (repo_id, branch, path, changed_commit_id, blob_id) \
+                                                          VALUES (?,?,?,?,?)"));
+
+            try!(insert_stmt.execute(&[&repo_id, &branch, &path_bytes, &changed_commit_id, &blob_id]));
+        }
+
+        Ok(())
+    }
+
+    /// the branches on which a path currently has the given content
+    pub fn find_branches_with_blob(&self, repo_id: &str, path: &Path, blob_id: &str) -> RepoResult<Vec<String>> {
+        let mut stmt = try!(self.conn.prepare("SELECT branch FROM files WHERE repo_id = ? AND path = ? AND blob_id = ? AND changed_commit_id IS NOT NULL ORDER BY branch"));
 
         let path_bytes = try!(path_to_bytes(path));
-        
-        try!(stmt.execute(&[&repo_id, &branch, &path_bytes, &changed_commit_id]));
+
+        let rows = try!(stmt.query(&[&repo_id, &path_bytes, &blob_id]));
+
+        let mut result = vec![];
+
+        for row_result in rows {
+            let row = try!(row_result);
+
+            result.push(row.get(0));
+        }
+
+        Ok(result)
+    }
+
+    /// record every file on a branch as deleted, so they're all removed from the index
+    pub fn mark_branch_files_deleted(&self, repo_id: &str, branch: &str) -> RepoResult<()> {
+        let mut stmt = try!(self.conn.prepare("UPDATE files SET changed_commit_id = NULL, blob_id = NULL WHERE repo_id = ? AND branch = ?"));
+
+        try!(stmt.execute(&[&repo_id, &branch]));
+
+        Ok(())
+    }
+
+    pub fn delete_file(&self, repo_id: &str, branch: &str, path: &Path) -> RepoResult<()> {
+        let mut stmt = try!(self.conn.prepare("DELETE FROM files WHERE repo_id = ? AND branch = ? AND path = ?"));
+
+        let path_bytes = try!(path_to_bytes(path));
+
+        try!(stmt.execute(&[&repo_id, &branch, &path_bytes]));
 
         Ok(())
     }
 
+    pub fn find_file(&self, repo_id: &str, branch: &str, path: &Path) -> RepoResult<Option<RepoFile>> {
+        let mut stmt = try!(self.conn.prepare("SELECT * FROM files WHERE repo_id = ? AND branch = ? AND path = ?"));
+
+        let path_bytes = try!(path_to_bytes(path));
+
+        let mut rows = try!(stmt.query(&[&repo_id, &branch, &path_bytes]));
+
+        match rows.next() {
+            Some(row_result) => {
+                let row = try!(row_result);
+
+                Ok(Some(try!(RepoFile::new_from_sql_row(&row))))
+            },
+            None => Ok(None)
+        }
+    }
+
     pub fn find_files_not_indexed(&self, repo_id: &str) -> RepoResult<Vec<RepoFile>> {
-        let mut stmt = try!(self.conn.prepare("SELECT * FROM files WHERE ((indexed_commit_id is null) or (indexed_commit_id != changed_commit_id)) AND repo_id = ?").map_err(|e| RepoError::SqlError(e)));
+        let mut stmt = try!(self.conn.prepare("SELECT * FROM files WHERE ((indexed_commit_id is null) or (changed_commit_id is null) or (indexed_commit_id != changed_commit_id) or (indexed_blob_id is null)) AND repo_id = ?").map_err(|e| RepoError::SqlError(e)));
         
         let rows = try!(stmt.query(&[&repo_id]));
 
@@ -198,19 +396,35 @@ impl Db {
         Ok(result)
     }
 
-    /// find file by repo_id and path, and set the indexed_commit_id column
-    pub fn mark_file_as_indexed(&self, repo_id: &str, path: &Path, indexed_commit_id: &str) -> RepoResult<()> {
+    /// find file by repo_id, branch and path, and set the indexed_commit_id, indexed_blob_id and indexed_symbol_count columns
+    pub fn mark_file_as_indexed(&self, repo_id: &str, branch: &str, path: &Path, indexed_commit_id: &str, indexed_blob_id: Option<&str>, indexed_symbol_count: usize) -> RepoResult<()> {
         let mut stmt = try!(self.conn.prepare("UPDATE files SET \
-                                               indexed_commit_id = ? \
-                                               WHERE path=? AND repo_id=?").map_err(|e| RepoError::SqlError(e)));
+                                               indexed_commit_id = ?, indexed_blob_id = ?, indexed_symbol_count = ? \
+                                               WHERE path=? AND repo_id=? AND branch=?").map_err(|e| RepoError::SqlError(e)));
 
         let path_bytes = try!(path_to_bytes(path));
+
+        let indexed_symbol_count = indexed_symbol_count as i64;
         
-        try!(stmt.execute(&[&indexed_commit_id, &path_bytes, &repo_id]));
+        try!(stmt.execute(&[&indexed_commit_id, &indexed_blob_id, &indexed_symbol_count, &path_bytes, &repo_id, &branch]));
         
         Ok(())        
     }
 
+    /// forget everything indexed for the repo: the work tables and the branches' indexed commits.
+    /// the branches themselves are still tracked
+    pub fn clear_repo_index_state(&self, repo_id: &str) -> RepoResult<()> {
+        for table in ["files", "commits"].iter() {
+            let mut stmt = try!(self.conn.prepare(&format!("DELETE FROM {} WHERE repo_id = ?", table)));
+            try!(stmt.execute(&[&repo_id]));
+        }
+
+        let mut branches_stmt = try!(self.conn.prepare("UPDATE branches SET indexed_commit_id = NULL WHERE repo_id = ?"));
+        try!(branches_stmt.execute(&[&repo_id]));
+
+        Ok(())
+    }
+
     /// in a single transaction, delete all rows in the commits and files work tables, for the given repo
     pub fn clear_commits(&self, repo_id: &str) -> RepoResult<()> {
         let mut del_commits_stmt = try!(self.conn.prepare("DELETE FROM commits WHERE repo_id = ?"));
@@ -229,10 +443,35 @@ impl Drop for Db {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::path::{Path,PathBuf};
 
     #[test]
     pub fn test_open_in_memory() {
         let db = Db::open_in_memory().unwrap();
         db.migrate();
     }
+
+    #[test]
+    pub fn test_clear_repo_index_state() {
+        let db = Db::open_in_memory().unwrap();
+        db.migrate();
+
+        for id in ["repo1", "repo2"].iter() {
+            db.insert_repo(&Repository::new_from_remote(id.to_string(), format!("https://example.com/{}.git", id), PathBuf::from(*id))).unwrap();
+            db.insert_branch(&RepoBranch::new(id.to_string(), "master".to_string(), Some("abc123".to_string()))).unwrap();
+            db.upsert_file(id, "master", Path::new("README"), Some("abc123"), Some("def456")).unwrap();
+            db.create_commit_unless_exists("abc123", id).unwrap();
+        }
+
+        db.clear_repo_index_state("repo1").unwrap();
+
+        assert_eq!(db.find_branch("repo1", "master").unwrap().unwrap().indexed_commit_id, None);
+        assert!(db.find_file("repo1", "master", Path::new("README")).unwrap().is_none());
+        assert_eq!(db.count_commits("repo1").unwrap(), 0);
+
+        // other repos are left alone
+        assert_eq!(db.find_branch("repo2", "master").unwrap().unwrap().indexed_commit_id, Some("abc123".to_string()));
+        assert!(db.find_file("repo2", "master", Path::new("README")).unwrap().is_some());
+        assert_eq!(db.count_commits("repo2").unwrap(), 1);
+    }
 }
@@ -0,0 +1,17 @@
+use std::collections::BTreeMap;
+use rustc_serialize::json::{Json,ToJson};
+
+/// builds a json object from its fields, for elasticsearch request bodies and api responses
+pub fn object(fields: Vec<(&str, Json)>) -> Json {
+    let mut map = BTreeMap::new();
+
+    for (key, value) in fields {
+        map.insert(key.to_string(), value);
+    }
+
+    Json::Object(map)
+}
+
+pub fn term(field: &str, value: &str) -> Json {
+    object(vec![("term", object(vec![(field, value.to_json())]))])
+}
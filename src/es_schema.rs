@@ -0,0 +1,216 @@
+use std::io::Read;
+use hyper;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use rustc_serialize::json::Json;
+use config::Config;
+use result::*;
+
+/// everything reads and writes through this alias,
+/// which points at a single versioned concrete index e.g. codelauf_v1
+pub const INDEX_ALIAS: &'static str = "codelauf";
+
+/// bump this whenever esindex.json changes, then run es-init to roll it out
+pub const MAPPING_VERSION: u32 = 5;
+
+const INDEX_DEFINITION: &'static str = include_str!("../esindex.json");
+
+pub fn concrete_index_name(version: u32) -> String {
+    format!("{}_v{}", INDEX_ALIAS, version)
+}
+
+/// parses the version out of a concrete index name like codelauf_v3
+pub fn parse_concrete_index_version(name: &str) -> Option<u32> {
+    let prefix = format!("{}_v", INDEX_ALIAS);
+
+    if name.starts_with(&prefix) {
+        name[prefix.len()..].parse::<u32>().ok()
+    } else {
+        None
+    }
+}
+
+/// creates and upgrades the elasticsearch index using the settings and mappings in esindex.json
+pub struct EsSchema {
+    base_url: String,
+    client: hyper::Client,
+}
+
+impl EsSchema {
+    pub fn new_for_config(config: &Config) -> RepoResult<EsSchema> {
+        let (es_host, es_port) = try!(config.get_elasticsearch());
+
+        Ok(EsSchema {
+            base_url: format!("http://{}:{}", es_host, es_port),
+            client: hyper::Client::new(),
+        })
+    }
+
+    fn request(&self, method: Method, path: &str, body: Option<&str>) -> RepoResult<(StatusCode, String)> {
+        let url = format!("{}/{}", self.base_url, path);
+
+        info!("elasticsearch {} {}", method, url);
+
+        let req = self.client.request(method, &url);
+
+        let mut res = match body {
+            Some(body) => try!(req.body(body).send()),
+            None => try!(req.send()),
+        };
+
+        let mut res_str = String::new();
+        try!(res.read_to_string(&mut res_str));
+
+        Ok((res.status, res_str))
+    }
+
+    fn expect_success(&self, method: Method, path: &str, body: Option<&str>) -> RepoResult<()> {
+        let (status, res_str) = try!(self.request(method, path, body));
+
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(RepoError::ElasticSearchResponseError(format!("{} {}", status, res_str)))
+        }
+    }
+
+    /// the mapping version of the index the alias currently points to, if any
+    pub fn current_version(&self) -> RepoResult<Option<u32>> {
+        let (status, res_str) = try!(self.request(Method::Get, &format!("_alias/{}", INDEX_ALIAS), None));
+
+        if status == StatusCode::NotFound {
+            return Ok(None);
+        } else if !status.is_success() {
+            return Err(RepoError::ElasticSearchResponseError(format!("{} {}", status, res_str)));
+        }
+
+        let res_json = try!(Json::from_str(&res_str).map_err(|e| RepoError::ElasticSearchResponseError(format!("{:?}", e))));
+
+        // {"codelauf_v1": {"aliases": {"codelauf": {}}}}
+        let indexes = try!(res_json.as_object().ok_or(RepoError::ElasticSearchResponseError(res_str.clone())));
+
+        Ok(indexes.keys().filter_map(|name| parse_concrete_index_version(name)).max())
+    }
+
+    /// true if there's a plain index called codelauf, e.g. one created by hand before aliases were used
+    pub fn unaliased_index_exists(&self) -> RepoResult<bool> {
+        let (status, _) = try!(self.request(Method::Head, INDEX_ALIAS, None));
+
+        Ok(status.is_success())
+    }
+
+    pub fn create_index(&self, version: u32) -> RepoResult<()> {
+        info!("creating index {}", concrete_index_name(version));
+
+        self.expect_success(Method::Put, &concrete_index_name(version), Some(INDEX_DEFINITION))
+    }
+
+    /// copy all documents from one concrete index into another
+    pub fn reindex(&self, from: u32, to: u32) -> RepoResult<()> {
+        self.reindex_named(&concrete_index_name(from), &concrete_index_name(to))
+    }
+
+    fn reindex_named(&self, from: &str, to: &str) -> RepoResult<()> {
+        info!("reindexing {} into {}", from, to);
+
+        let body = format!(r#"{{"source": {{"index": "{}"}}, "dest": {{"index": "{}"}}}}"#, from, to);
+
+        self.expect_success(Method::Post, "_reindex", Some(&body))
+    }
+
+    /// move the documents of a plain codelauf index into a versioned one,
+    /// then delete it so its name can be used for the alias.
+    /// the versioned index may already exist if an earlier attempt was interrupted
+    pub fn migrate_unaliased_index(&self) -> RepoResult<()> {
+        info!("migrating plain index {} to {}", INDEX_ALIAS, concrete_index_name(MAPPING_VERSION));
+
+        let (status, _) = try!(self.request(Method::Head, &concrete_index_name(MAPPING_VERSION), None));
+
+        if !status.is_success() {
+            try!(self.create_index(MAPPING_VERSION));
+        }
+
+        try!(self.reindex_named(INDEX_ALIAS, &concrete_index_name(MAPPING_VERSION)));
+
+        info!("deleting plain index {}", INDEX_ALIAS);
+
+        self.expect_success(Method::Delete, INDEX_ALIAS, None)
+    }
+
+    /// atomically point the alias at a new concrete index
+    pub fn swap_alias(&self, from: Option<u32>, to: u32) -> RepoResult<()> {
+        info!("pointing alias {} at {}", INDEX_ALIAS, concrete_index_name(to));
+
+        let add = format!(r#"{{"add": {{"index": "{}", "alias": "{}"}}}}"#, concrete_index_name(to), INDEX_ALIAS);
+
+        let actions = match from {
+            Some(from) => format!(r#"{{"remove": {{"index": "{}", "alias": "{}"}}}}, {}"#, concrete_index_name(from), INDEX_ALIAS, add),
+            None => add,
+        };
+
+        self.expect_success(Method::Post, "_aliases", Some(&format!(r#"{{"actions": [{}]}}"#, actions)))
+    }
+
+    /// called when the worker starts.
+    /// creates the index if there isn't one, and fails if it's an older version
+    /// because rolling out a new mapping is done deliberately with es-init
+    pub fn ensure_index(&self) -> RepoResult<()> {
+        match try!(self.current_version()) {
+            None => {
+                self.init()
+            },
+            Some(version) if version == MAPPING_VERSION => {
+                info!("index {} is up to date", concrete_index_name(version));
+                Ok(())
+            },
+            Some(version) => {
+                Err(RepoError::IndexVersionMismatch(version, MAPPING_VERSION))
+            }
+        }
+    }
+
+    /// create the index, or if it's an older version, create a new one,
+    /// copy the documents over and swap the alias.
+    /// the old index is left in place to be deleted by hand,
+    /// except for a plain codelauf index, which has to go to make way for the alias.
+    pub fn init(&self) -> RepoResult<()> {
+        match try!(self.current_version()) {
+            None => {
+                if try!(self.unaliased_index_exists()) {
+                    try!(self.migrate_unaliased_index());
+                } else {
+                    try!(self.create_index(MAPPING_VERSION));
+                }
+
+                self.swap_alias(None, MAPPING_VERSION)
+            },
+            Some(version) if version == MAPPING_VERSION => {
+                info!("index {} is up to date", concrete_index_name(version));
+                Ok(())
+            },
+            Some(version) if version < MAPPING_VERSION => {
+                try!(self.create_index(MAPPING_VERSION));
+                try!(self.reindex(version, MAPPING_VERSION));
+                try!(self.swap_alias(Some(version), MAPPING_VERSION));
+
+                info!("old index {} can now be deleted", concrete_index_name(version));
+                Ok(())
+            },
+            Some(version) => {
+                Err(RepoError::IndexVersionMismatch(version, MAPPING_VERSION))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_concrete_index_version() {
+        assert_eq!(concrete_index_name(3), "codelauf_v3");
+        assert_eq!(parse_concrete_index_version("codelauf_v3"), Some(3));
+        assert_eq!(parse_concrete_index_version("codelauf"), None);
+    }
+}
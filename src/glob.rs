@@ -0,0 +1,111 @@
+/// matches shell style patterns against paths and branch names.
+/// `*` matches anything within one path segment, `**` matches across segments,
+/// and `?` matches a single character other than `/`.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+
+    match_from(&p, &t)
+}
+
+fn match_from(p: &[char], t: &[char]) -> bool {
+    if p.is_empty() {
+        return t.is_empty();
+    }
+
+    if p[0] == '*' {
+        if p.len() > 1 && p[1] == '*' {
+            let rest = &p[2..];
+
+            // so that a/**/b also matches a/b
+            let rest_without_slash = if rest.first() == Some(&'/') { &rest[1..] } else { rest };
+
+            for i in 0..t.len() + 1 {
+                if match_from(rest, &t[i..]) || match_from(rest_without_slash, &t[i..]) {
+                    return true;
+                }
+            }
+
+            false
+        } else {
+            let rest = &p[1..];
+
+            for i in 0..t.len() + 1 {
+                if match_from(rest, &t[i..]) {
+                    return true;
+                }
+
+                if i < t.len() && t[i] == '/' {
+                    break;
+                }
+            }
+
+            false
+        }
+    } else if t.is_empty() {
+        false
+    } else if p[0] == '?' {
+        t[0] != '/' && match_from(&p[1..], &t[1..])
+    } else {
+        p[0] == t[0] && match_from(&p[1..], &t[1..])
+    }
+}
+
+/// the same pattern as a lucene regular expression, for filtering on paths in elasticsearch
+pub fn glob_to_regexp(pattern: &str) -> String {
+    let p: Vec<char> = pattern.chars().collect();
+    let mut regexp = String::new();
+    let mut i = 0;
+
+    while i < p.len() {
+        match p[i] {
+            '*' if i + 1 < p.len() && p[i + 1] == '*' => {
+                if i + 2 < p.len() && p[i + 2] == '/' {
+                    // so that a/**/b also matches a/b
+                    regexp.push_str("(.*/)?");
+                    i += 3;
+                } else {
+                    regexp.push_str(".*");
+                    i += 2;
+                }
+                continue;
+            },
+            '*' => regexp.push_str("[^/]*"),
+            '?' => regexp.push_str("[^/]"),
+            c if ".+|{}[]()\"\\#@&<>~".contains(c) => {
+                regexp.push('\\');
+                regexp.push(c);
+            },
+            c => regexp.push(c),
+        }
+
+        i += 1;
+    }
+
+    regexp
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_glob_match() {
+        assert!(glob_match("src/*.rs", "src/lib.rs"));
+        assert!(!glob_match("src/*.rs", "src/models/mod.rs"));
+        assert!(glob_match("src/**/*.rs", "src/models/mod.rs"));
+        assert!(glob_match("src/**/*.rs", "src/lib.rs"));
+        assert!(glob_match("release/*", "release/1.0"));
+        assert!(!glob_match("release/*", "master"));
+        assert!(glob_match("v?.?", "v1.2"));
+        assert!(glob_match("master", "master"));
+    }
+
+    #[test]
+    pub fn test_glob_to_regexp() {
+        assert_eq!(glob_to_regexp("src/*.rs"), "src/[^/]*\\.rs");
+        assert_eq!(glob_to_regexp("src/**/*.rs"), "src/(.*/)?[^/]*\\.rs");
+        assert_eq!(glob_to_regexp("docs/**"), "docs/.*");
+        assert_eq!(glob_to_regexp("v?.?"), "v[^/]\\.[^/]");
+    }
+}
@@ -1,15 +1,21 @@
-use repo::Repo;
+use repo::{Repo,SyncState};
 use result::*;
 use config::*;
 use db::*;
+use bulk::*;
+use es_schema::INDEX_ALIAS;
+use analyser::{Analysers,Definition};
+use es_query::{object,term};
+use hyper;
+use rustc_serialize::json::{Json,ToJson};
+use std::io::Read;
 use git2;
 use chrono::*;
 use rs_es;
 use sha1::Sha1;
-use std::fs::File;
 use std::path::{Path,PathBuf};
 use std::cell::RefCell;
-use std::io::Read;
+use std::collections::HashMap;
 use encoding::{Encoding, DecoderTrap};
 use encoding::all::UTF_8;
 
@@ -41,6 +47,78 @@ impl Signature {
     }
 }
 
+/// stop collecting a file's added or removed lines once there's this much,
+/// so one huge file doesn't crowd out the rest of the commit
+const MAX_COMMIT_FILE_TEXT: usize = 100 * 1024;
+
+/// and stop collecting lines for any file once the commit has this much in total,
+/// so that e.g. vendoring a library of thousands of files doesn't make an enormous commit document
+const MAX_COMMIT_TEXT: usize = 1024 * 1024;
+
+/// documents deleted per bulk request when clearing a repo out of the index
+const DELETE_PAGE_SIZE: u64 = 500;
+
+/// how long elasticsearch keeps a scroll open between pages
+const SCROLL_KEEP_ALIVE: &'static str = "1m";
+
+/// a file changed by a commit
+#[derive(Debug,Clone,RustcEncodable,RustcDecodable)]
+pub struct CommitFile {
+    pub change_type: String,
+    pub path: PathBuf,
+    pub old_path: Option<PathBuf>, // where a renamed or copied file came from
+    pub text: Option<String>, // lines added
+    pub removed_text: Option<String>, // lines removed
+    pub keywords: Option<String>, // definitions and imports in the added lines
+}
+
+impl CommitFile {
+    pub fn new(change_type: &str, path: PathBuf, old_path: Option<PathBuf>) -> CommitFile {
+        CommitFile {
+            change_type: change_type.to_owned(),
+            path: path,
+            old_path: old_path,
+            text: None,
+            removed_text: None,
+            keywords: None,
+        }
+    }
+
+    pub fn change_type_for_delta(delta: git2::Delta) -> &'static str {
+        match delta {
+            git2::Delta::Added => "added",
+            git2::Delta::Deleted => "deleted",
+            git2::Delta::Renamed => "renamed",
+            git2::Delta::Copied => "copied",
+            _ => "modified",
+        }
+    }
+
+    /// record a line of the patch. context lines and headers are ignored.
+    /// returns the number of bytes kept
+    pub fn add_line(&mut self, origin: char, content: &[u8]) -> usize {
+        let target = match origin {
+            '+' => &mut self.text,
+            '-' => &mut self.removed_text,
+            _ => return 0,
+        };
+
+        if target.is_none() {
+            *target = Some(String::new());
+        }
+
+        let text = target.as_mut().unwrap();
+
+        if text.len() < MAX_COMMIT_FILE_TEXT {
+            let line = String::from_utf8_lossy(content);
+            text.push_str(&line);
+            line.len()
+        } else {
+            0
+        }
+    }
+}
+
 #[derive(Debug,Clone,RustcEncodable,RustcDecodable)]
 pub struct Commit {
     pub parents: Vec<CommitId>,
@@ -49,6 +127,7 @@ pub struct Commit {
     pub committer: Signature,
     pub commit_date: String,
     pub message: Option<String>,
+    pub files: Option<Vec<CommitFile>>, // missing from documents indexed before files were added
 }
 
 impl Commit {
@@ -68,6 +147,7 @@ impl Commit {
             committer: Signature::new_for_git_signature(&commit.committer()),
             commit_date: time.to_rfc3339(),
             message: commit.message().map(|s| s.to_owned()),
+            files: None,
         })
     }
 }
@@ -76,6 +156,8 @@ impl Commit {
 pub struct IndexedFile {
     pub repo_id: String,
     pub path: PathBuf,
+    pub blob_id: String,
+    pub branches: Vec<String>, // every branch on which the path has this content
     pub text: Option<String>,
     pub keywords: Option<String>,
     pub changed_commit_id: Option<String>,
@@ -83,10 +165,12 @@ pub struct IndexedFile {
 }
 
 impl IndexedFile {
-    pub fn new(repo_id: String, path: PathBuf) -> IndexedFile {
+    pub fn new(repo_id: String, path: PathBuf, blob_id: String) -> IndexedFile {
         IndexedFile {
             repo_id: repo_id,
             path: path,
+            blob_id: blob_id,
+            branches: vec![],
             text: None,
             keywords: None,
             changed_commit_id: None,
@@ -95,75 +179,242 @@ impl IndexedFile {
     }
 
     pub fn id(&self) -> String {
+        IndexedFile::id_for(&self.repo_id, &self.path, &self.blob_id)
+    }
+
+    /// a path with the same content on several branches is a single document
+    pub fn id_for(repo_id: &str, path: &Path, blob_id: &str) -> String {
+        let mut h = Sha1::new();
+        h.update(repo_id.as_bytes());
+        h.update(path_to_bytes(path).unwrap());
+        h.update(blob_id.as_bytes());
+        h.hexdigest()
+    }
+
+    /// file documents indexed before they were shared between branches didn't include the blob id
+    pub fn id_without_blob(repo_id: &str, path: &Path) -> String {
+        let mut h = Sha1::new();
+        h.update(repo_id.as_bytes());
+        h.update(path_to_bytes(path).unwrap());
+        h.hexdigest()
+    }
+}
+
+/// where something is defined, as of a branch tip
+#[derive(Debug,Clone,RustcEncodable,RustcDecodable)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: String,
+    pub language: String,
+    pub repo_id: String,
+    pub branch: String,
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub scope: Option<String>,
+}
+
+impl Symbol {
+    pub fn new_for_definition(repo_id: &str, branch: &str, path: &Path, language: &str, definition: &Definition) -> Symbol {
+        Symbol {
+            name: definition.name.clone(),
+            kind: definition.kind.to_owned(),
+            language: language.to_owned(),
+            repo_id: repo_id.to_owned(),
+            branch: branch.to_owned(),
+            path: path.to_owned(),
+            line: definition.line,
+            column: definition.column,
+            scope: definition.scope.clone(),
+        }
+    }
+
+    /// symbols are numbered in the order they appear in the file,
+    /// so a new version of a file overwrites the documents of the old one
+    pub fn id(repo_id: &str, branch: &str, path: &Path, ordinal: usize) -> String {
         let mut h = Sha1::new();
-        h.update(self.repo_id.as_bytes());
-        h.update(path_to_bytes(&self.path).unwrap());
+        h.update(repo_id.as_bytes());
+        h.update(branch.as_bytes());
+        h.update(path_to_bytes(path).unwrap());
+        h.update(format!("{}", ordinal).as_bytes());
         h.hexdigest()
     }
 }
 
 pub struct Index {
+    pub es_base_url: String,
     pub es_client: RefCell<rs_es::Client>,
+    pub bulk: RefCell<Bulk>,
+    pub analysers: Analysers,
 }
 
 impl Index {
     pub fn new_for_config(config: &Config) -> RepoResult<Index> {
-        let es_url_str: &str = try!(config.elasticsearch.as_ref().ok_or(RepoError::NoElasticSearch));
-
-        let mut es_url_parts = es_url_str.split(":");
-        
-        let es_host = try!(es_url_parts.next().ok_or(RepoError::NoElasticSearch));
-        let es_port = try!(es_url_parts.next().map(|s| s.parse::<u32>()).unwrap_or(Ok(9200)));
+        let (es_host, es_port) = try!(config.get_elasticsearch());
 
         info!("es host: {} port: {}", es_host, es_port);
 
+        let es_base_url = format!("http://{}:{}", es_host, es_port);
+
         Ok(Index {
-            es_client: RefCell::new(rs_es::Client::new(es_host, es_port)),
+            es_base_url: es_base_url.clone(),
+            es_client: RefCell::new(rs_es::Client::new(&es_host, es_port)),
+            bulk: RefCell::new(Bulk::new(&es_base_url, INDEX_ALIAS, &config.index_config)),
+            analysers: Analysers::new_with_defaults(),
         })
     }
 
-    pub fn index_tree(&self, db: &Db, repo: &Repo) -> RepoResult<()> {
+    /// queue a file document. the files table is updated once elasticsearch accepts it,
+    /// along with everything else queued for the file
+    fn queue_file(&self, db: &Db, repo: &Repo, branch: &str, indexed_file: &IndexedFile, commit_id: &str, symbol_count: usize) -> RepoResult<()> {
+        let mut bulk = self.bulk.borrow_mut();
+
+        try!(bulk.index("file", &indexed_file.id(), indexed_file, BulkDone::Nothing));
+
+        bulk.end_group(db, &repo.id, BulkDone::File(branch.to_owned(), indexed_file.path.clone(), commit_id.to_owned(), indexed_file.blob_id.clone(), symbol_count))
+    }
+
+    /// mark a file indexed without a document, once what was queued for it has been accepted
+    fn end_file(&self, db: &Db, repo: &Repo, done: BulkDone) -> RepoResult<()> {
+        self.bulk.borrow_mut().end_group(db, &repo.id, done)
+    }
+
+    /// send anything still queued, returning the number of documents rejected since the last call
+    pub fn flush(&self, db: &Db, repo: &Repo) -> RepoResult<usize> {
+        let mut bulk = self.bulk.borrow_mut();
+
+        try!(bulk.flush(db, &repo.id));
+
+        Ok(bulk.take_failures())
+    }
+
+    /// index everything in the files work table that has changed since it was last indexed,
+    /// and remove files that have been deleted.
+    /// returns the number of files that couldn't be indexed
+    pub fn index_tree(&self, db: &Db, repo: &Repo) -> RepoResult<usize> {
         let files = try!(db.find_files_not_indexed(&repo.id));
 
-        for file in files {
-            match self.index_file(db, repo, &file.path, &file.changed_commit_id) {
+        let total = files.len();
+        let mut errors = 0;
+
+        for (i, file) in files.iter().enumerate() {
+            repo.progress.report(&repo.id, SyncState::IndexingFiles, i, total);
+
+            let result = match file.changed_commit_id {
+                Some(ref commit_id) => self.index_file(db, repo, file, commit_id),
+                None => self.delete_file(db, repo, file),
+            };
+
+            match result {
                 Err(err) => {
                     info!("error indexing file {:?}: {:?}", file.path, err);
+                    errors += 1;
                 },
                 _ => {}
             }
         }
 
-        Ok(())
+        let failures = errors + try!(self.flush(db, repo));
+        if failures > 0 {
+            error!("{} files of {} not indexed. will retry next sync", failures, repo.uri);
+        }
+
+        repo.progress.report(&repo.id, SyncState::IndexingFiles, total, total);
+
+        Ok(failures)
     }
 
-    pub fn index_file(&self, db: &Db, repo: &Repo, path: &Path, commit_id: &str) -> RepoResult<()> {
+    /// index a file as it was at the given commit, and take the branch off the document
+    /// for the content it had before. the file is only marked indexed once both are accepted
+    pub fn index_file(&self, db: &Db, repo: &Repo, file: &RepoFile, commit_id: &str) -> RepoResult<()> {
+        let branch = &file.branch;
+        let path = &file.path;
+
         info!("indexing file {:?}", path);
 
-        let mut f = try!(File::open(path));
-        let mut s = String::new();
-        try!(f.read_to_string(&mut s));
-        //todo analyse file instead of sending verbatim
+        self.bulk.borrow_mut().begin_group();
 
-        let mut indexed_file = IndexedFile::new(repo.id.clone(), path.to_owned());
-        indexed_file.text = Some(s);
-        indexed_file.changed_commit_id = Some(commit_id.to_owned());
-        let file_id = indexed_file.id();
-        
-        let mut es_client = self.es_client.borrow_mut();
-        let mut op = es_client.index("codelauf", "file");
-        
-        try!(op
-             .with_id(&file_id)
-             .with_doc(&indexed_file)
-             .send());
+        let git_repo = try!(repo.git_repo());
 
-        try!(db.mark_file_as_indexed(&repo.id, path, commit_id));
+        let commit = try!(repo.get_commit(commit_id));
+        let tree = try!(commit.tree());
+        let entry = try!(tree.get_path(path));
+
+        let blob_id = format!("{}", entry.id());
+
+        // rows from before blob ids were recorded need one, or the document won't list this branch
+        if file.blob_id.as_ref() != Some(&blob_id) {
+            try!(db.upsert_file(&repo.id, branch, path, Some(commit_id), Some(&blob_id)));
+        }
+
+        match file.indexed_blob_id {
+            Some(ref old_blob_id) if *old_blob_id != blob_id => {
+                try!(self.remove_branch_from_blob(db, repo, path, old_blob_id));
+            },
+            Some(_) => {},
+            None => {
+                if file.indexed_commit_id.is_some() {
+                    self.bulk.borrow_mut().delete("file", &IndexedFile::id_without_blob(&repo.id, path), BulkDone::Nothing);
+                }
+            }
+        }
+
+        match entry.kind() {
+            Some(git2::ObjectType::Blob) => {
+                let blob = try!(git_repo.find_blob(entry.id()));
+
+                self.index_blob(db, repo, branch, path, commit_id, &blob)
+            },
+            _ => {
+                info!("not indexing {:?}: not a blob", path);
+
+                try!(self.replace_symbols(db, repo, branch, path, vec![]));
+
+                self.end_file(db, repo, BulkDone::File(branch.to_owned(), path.to_owned(), commit_id.to_owned(), blob_id, 0))
+            }
+        }
+    }
+
+    /// take a deleted file's branch off its document. the files table row is removed once elasticsearch has accepted that
+    pub fn delete_file(&self, db: &Db, repo: &Repo, file: &RepoFile) -> RepoResult<()> {
+        let branch = &file.branch;
+        let path = &file.path;
+
+        info!("removing file {:?} from index", path);
+
+        self.bulk.borrow_mut().begin_group();
+
+        try!(self.replace_symbols(db, repo, branch, path, vec![]));
+
+        match file.indexed_blob_id {
+            Some(ref old_blob_id) => try!(self.remove_branch_from_blob(db, repo, path, old_blob_id)),
+            None => self.bulk.borrow_mut().delete("file", &IndexedFile::id_without_blob(&repo.id, path), BulkDone::Nothing),
+        }
+
+        self.end_file(db, repo, BulkDone::FileDeleted(branch.to_owned(), path.to_owned()))
+    }
+
+    /// a branch no longer has this content at this path. update the document's branches
+    /// from the files table, or delete the document if no branch has the content any more
+    fn remove_branch_from_blob(&self, db: &Db, repo: &Repo, path: &Path, blob_id: &str) -> RepoResult<()> {
+        let branches = try!(db.find_branches_with_blob(&repo.id, path, blob_id));
+
+        let id = IndexedFile::id_for(&repo.id, path, blob_id);
+
+        let mut bulk = self.bulk.borrow_mut();
+
+        if branches.is_empty() {
+            bulk.delete("file", &id, BulkDone::Nothing);
+        } else {
+            bulk.update("file", &id, object(vec![("branches", branches.to_json())]), BulkDone::Nothing);
+        }
 
         Ok(())
     }
 
-    pub fn index_blob(&self, db: &Db, repo: &Repo, path: &Path, commit_id: &str, blob: &git2::Blob) -> RepoResult<()> {
+    pub fn index_blob(&self, db: &Db, repo: &Repo, branch: &str, path: &Path, commit_id: &str, blob: &git2::Blob) -> RepoResult<()> {
+        let blob_id = format!("{}", blob.id());
+
         if blob.is_binary() {
             info!("not indexing binary file {:?}", path);
         } else {
@@ -176,31 +427,181 @@ impl Index {
             } else {
                 let blob_str = maybe_blob_str.unwrap();
                 
-                try!(self.index_blob_str(db, repo, path, commit_id, &blob_str));
+                return self.index_blob_str(db, repo, branch, path, commit_id, &blob_id, &blob_str);
             }
         }
 
-        Ok(())
+        try!(self.replace_symbols(db, repo, branch, path, vec![]));
+
+        // no document to send, but don't keep trying
+        self.end_file(db, repo, BulkDone::File(branch.to_owned(), path.to_owned(), commit_id.to_owned(), blob_id, 0))
     }
 
-    pub fn index_blob_str(&self, db: &Db, repo: &Repo, path: &Path, commit_id: &str, blob: &str) -> RepoResult<()> {
-        let mut indexed_file = IndexedFile::new(repo.id.clone(), path.to_owned());
+    pub fn index_blob_str(&self, db: &Db, repo: &Repo, branch: &str, path: &Path, commit_id: &str, blob_id: &str, blob: &str) -> RepoResult<()> {
+        let analysis = self.analysers.analyse(path, blob);
+
+        let mut indexed_file = IndexedFile::new(repo.id.clone(), path.to_owned(), blob_id.to_owned());
+        indexed_file.branches = try!(db.find_branches_with_blob(&repo.id, path, blob_id));
         indexed_file.text = Some(blob.to_owned());
+        indexed_file.keywords = analysis.as_ref().map(|analysis| analysis.keywords());
         indexed_file.changed_commit_id = Some(commit_id.to_owned());
-        let file_id = indexed_file.id();
-        
-        let mut es_client = self.es_client.borrow_mut();
-        let mut op = es_client.index("codelauf", "file");
-        
-        try!(op
-             .with_id(&file_id)
-             .with_doc(&indexed_file)
-             .send());
 
-        try!(db.mark_file_as_indexed(&repo.id, path, commit_id));
+        let commit = try!(repo.get_commit(commit_id));
+        indexed_file.changed_date = Some(Index::datetime_convert_git_to_chrono(&commit.time()).to_rfc3339());
+
+        let symbols = match analysis {
+            Some(ref analysis) => analysis.definitions.iter().map(|d| Symbol::new_for_definition(&repo.id, branch, path, analysis.language, d)).collect(),
+            None => vec![],
+        };
+
+        let symbol_count = try!(self.replace_symbols(db, repo, branch, path, symbols));
+
+        self.queue_file(db, repo, branch, &indexed_file, commit_id, symbol_count)
+    }
+
+    /// ids of the symbol documents for a file on a branch.
+    /// only needed for files indexed before their symbol count was recorded
+    fn find_symbol_ids(&self, repo_id: &str, branch: &str, path: &Path) -> RepoResult<Vec<String>> {
+        let query = object(vec![
+            ("size", 10000u64.to_json()),
+            ("_source", false.to_json()),
+            ("query", object(vec![("filtered", object(vec![
+                ("filter", object(vec![("bool", object(vec![("must", Json::Array(vec![
+                    term("repo_id", repo_id),
+                    term("branch", branch),
+                    term("path", &path.to_string_lossy()),
+                ]))]))])),
+            ]))])),
+        ]);
+
+        let url = format!("{}/{}/symbol/_search", self.es_base_url, INDEX_ALIAS);
+
+        let client = hyper::Client::new();
+        let mut res = try!(client.post(&url).body(&query.to_string()[..]).send());
+
+        let mut res_str = String::new();
+        try!(res.read_to_string(&mut res_str));
+
+        if !res.status.is_success() {
+            return Err(RepoError::ElasticSearchResponseError(format!("{} {}", res.status, res_str)));
+        }
+
+        let res_json = try!(Json::from_str(&res_str).map_err(|e| RepoError::ElasticSearchResponseError(format!("{:?}", e))));
+
+        let hits = try!(res_json.find_path(&["hits", "hits"])
+                        .and_then(|h| h.as_array())
+                        .ok_or(RepoError::ElasticSearchResponseError(res_str.clone())));
+
+        Ok(hits.iter().filter_map(|hit| hit.find("_id").and_then(|id| id.as_string()).map(|id| id.to_owned())).collect())
+    }
+
+    /// delete every file, commit and symbol document of the repo.
+    /// elasticsearch 2 only has delete by query as a plugin, so scroll through them and bulk delete each page
+    pub fn delete_repo_documents(&self, repo_id: &str) -> RepoResult<()> {
+        info!("deleting documents of repo {}", repo_id);
+
+        let query = object(vec![
+            ("size", DELETE_PAGE_SIZE.to_json()),
+            ("_source", false.to_json()),
+            ("sort", Json::Array(vec!["_doc".to_json()])),
+            ("query", term("repo_id", repo_id)),
+        ]);
+
+        let url = format!("{}/{}/_search?scroll={}", self.es_base_url, INDEX_ALIAS, SCROLL_KEEP_ALIVE);
+
+        let mut page = try!(self.post_json(&url, &query.to_string()));
+
+        loop {
+            let scroll_id = try!(page.find("_scroll_id")
+                                 .and_then(|id| id.as_string())
+                                 .map(|id| id.to_owned())
+                                 .ok_or(RepoError::ElasticSearchResponseError(page.to_string())));
+
+            let mut body = String::new();
+
+            {
+                let hits = try!(page.find_path(&["hits", "hits"])
+                                .and_then(|h| h.as_array())
+                                .ok_or(RepoError::ElasticSearchResponseError(page.to_string())));
+
+                if hits.is_empty() {
+                    break;
+                }
+
+                for hit in hits {
+                    let meta: Vec<(&str, Json)> = ["_index", "_type", "_id"].iter()
+                        .map(|key| (*key, hit.find(*key).cloned().unwrap_or(Json::Null)))
+                        .collect();
+
+                    body.push_str(&object(vec![("delete", object(meta))]).to_string());
+                    body.push('\n');
+                }
+            }
+
+            let res_json = try!(self.post_json(&format!("{}/_bulk", self.es_base_url), &body));
+
+            if res_json.find("errors").and_then(|e| e.as_boolean()).unwrap_or(true) {
+                return Err(RepoError::ElasticSearchResponseError(res_json.to_string()));
+            }
+
+            let next = object(vec![
+                ("scroll", SCROLL_KEEP_ALIVE.to_json()),
+                ("scroll_id", scroll_id.to_json()),
+            ]);
+
+            page = try!(self.post_json(&format!("{}/_search/scroll", self.es_base_url), &next.to_string()));
+        }
 
         Ok(())
     }
+
+    /// post a request body and parse the response, which must be a success
+    fn post_json(&self, url: &str, body: &str) -> RepoResult<Json> {
+        let client = hyper::Client::new();
+        let mut res = try!(client.post(url).body(body).send());
+
+        let mut res_str = String::new();
+        try!(res.read_to_string(&mut res_str));
+
+        if !res.status.is_success() {
+            return Err(RepoError::ElasticSearchResponseError(format!("{} {}", res.status, res_str)));
+        }
+
+        Json::from_str(&res_str).map_err(|e| RepoError::ElasticSearchResponseError(format!("{:?}", e)))
+    }
+
+    /// queue the symbols now defined in a file, and removal of any left over from its previous version.
+    /// symbol ids are numbered, so the leftovers are the ones numbered from the new count up to the old one.
+    /// returns the new count, to be recorded in the files table with the file.
+    /// called in the file's bulk group, so the count is only recorded if these are all accepted,
+    /// and otherwise the same leftovers are found again next sync
+    pub fn replace_symbols(&self, db: &Db, repo: &Repo, branch: &str, path: &Path, symbols: Vec<Symbol>) -> RepoResult<usize> {
+        let old_file = try!(db.find_file(&repo.id, branch, path));
+
+        let stale_ids: Vec<String> = match old_file {
+            Some(RepoFile { indexed_symbol_count: Some(old_count), .. }) => {
+                (symbols.len()..old_count).map(|i| Symbol::id(&repo.id, branch, path, i)).collect()
+            },
+            Some(RepoFile { indexed_commit_id: Some(_), .. }) => {
+                let new_ids: Vec<String> = (0..symbols.len()).map(|i| Symbol::id(&repo.id, branch, path, i)).collect();
+
+                try!(self.find_symbol_ids(&repo.id, branch, path)).into_iter().filter(|id| !new_ids.contains(id)).collect()
+            },
+            _ => vec![]
+        };
+
+        let mut bulk = self.bulk.borrow_mut();
+
+        for (i, symbol) in symbols.iter().enumerate() {
+            try!(bulk.index("symbol", &Symbol::id(&repo.id, branch, path, i), symbol, BulkDone::Nothing));
+        }
+
+        for id in stale_ids {
+            bulk.delete("symbol", &id, BulkDone::Nothing);
+        }
+
+        Ok(symbols.len())
+    }
     
     pub fn index_repo(&self, db: &Db, repo: &Repo) -> RepoResult<()> {
         try!(self.index_commits(db, repo));
@@ -210,72 +611,80 @@ impl Index {
         Ok(())
     }
 
+    /// index the files work table populated by Repo::treewalks, along with the symbols each file defines,
+    /// and remove the files of retired refs. if everything made it into the index, record each
+    /// branch tip as indexed and stop tracking the retired refs
     pub fn index_branches(&self, db: &Db, repo: &Repo) -> RepoResult<()> {
-        let git_repo = try!(repo.git_repo());
-        
-        for branch in repo.branches.iter() {
-            let maybe_repo_branch = try!(db.find_branch(&repo.id, &branch.name));
+        for branch in repo.retired_branches.iter() {
+            try!(db.mark_branch_files_deleted(&repo.id, branch));
+        }
 
-            let repo_branch = try!(maybe_repo_branch.ok_or(RepoError::BranchNotFound));
+        let failures = try!(self.index_tree(db, repo));
 
-            let old_tree = match repo_branch.indexed_commit_id {
-                Some(commit) => {
-                    let commit = try!(repo.get_commit(&commit));
-                    let tree = try!(commit.tree());
-                    Some(tree)
-                },
-                None => None
-            };
+        if failures > 0 {
+            return Ok(());
+        }
 
+        for branch in repo.branches.iter() {
             let branch_commit_id = try!(repo.branch_commit_id(&branch.name));
             let branch_commit_id_str = format!("{}", branch_commit_id);
-            
-            let new_tree_commit = try!(repo.get_commit(&branch_commit_id_str));
-            let new_tree = try!(new_tree_commit.tree());
-
-            let mut diff_opts = git2::DiffOptions::new();
-            diff_opts.ignore_whitespace(true)
-                .ignore_filemode(true)
-                ;
 
-            let diff = try!(git2::Diff::tree_to_tree(git_repo, old_tree.as_ref(), Some(&new_tree), Some(&mut diff_opts)));
+            try!(db.mark_branch_as_indexed(&repo.id, &branch.name, &branch_commit_id_str));
+        }
 
-            try!(self.index_diff(db, repo, &branch_commit_id_str, &diff));
+        for branch in repo.retired_branches.iter() {
+            info!("no longer tracking {} of {}", branch, repo.uri);
 
-            try!(db.mark_branch_as_indexed(&repo.id, &branch.name, &branch_commit_id_str));
+            try!(db.delete_branch(&repo.id, branch));
         }
 
         Ok(())
     }
 
-    pub fn index_diff(&self, db: &Db, repo: &Repo, commit_id: &str, diff: &git2::Diff) -> RepoResult<()> {
-        let git_repo = try!(repo.git_repo());
-        
-        for delta in diff.deltas() {
-            let old_file = delta.old_file();
-            let new_file = delta.new_file();
-            
-            info!("delta: {:?} {:?} {:?} {:?} {:?}", delta.status(), old_file.id(), old_file.path(), new_file.id(), new_file.path());
-
-            let path = new_file.path();
-            
-            if !new_file.id().is_zero() && path.is_some() {
-                let blob = try!(git_repo.find_blob(new_file.id()));
-                try!(self.index_blob(db, repo, path.unwrap(), commit_id, &blob));
-            }
+    /// remove from the index commits that are no longer on any tracked branch,
+    /// e.g. after upstream was force pushed
+    pub fn delete_orphaned_commits(&self, db: &Db, repo: &Repo) -> RepoResult<()> {
+        let orphans = try!(db.find_orphaned_commits(&repo.id));
+
+        if orphans.is_empty() {
+            return Ok(());
         }
-        
-        Ok(())
+
+        info!("removing {} orphaned commits of {} from index", orphans.len(), repo.uri);
+
+        let mut bulk = self.bulk.borrow_mut();
+
+        for commit_id in orphans {
+            bulk.delete("commit", &commit_id, BulkDone::CommitDeleted(commit_id.clone()));
+
+            try!(bulk.maybe_flush(db, &repo.id));
+        }
+
+        bulk.flush(db, &repo.id)
     }
-    
+
     pub fn index_commits(&self, db: &Db, repo: &Repo) -> RepoResult<()> {
+        try!(self.delete_orphaned_commits(db, repo));
+
         let commits = try!(db.find_commits_not_indexed(&repo.id));
 
-        for commit in commits {
+        let total = try!(db.count_commits(&repo.id));
+        let already_indexed = total - commits.len();
+
+        for (i, commit) in commits.iter().enumerate() {
+            repo.progress.report(&repo.id, SyncState::IndexingCommits, already_indexed + i, total);
+
             info!("indexing {:?}", commit);
 
             try!(self.index_commit(db, repo, &commit));
         }
+
+        let failures = try!(self.flush(db, repo));
+        if failures > 0 {
+            error!("{} commits of {} not indexed. will retry next sync", failures, repo.uri);
+        }
+
+        repo.progress.report(&repo.id, SyncState::IndexingCommits, total, total);
         
         Ok(())
     }
@@ -283,38 +692,93 @@ impl Index {
     pub fn index_commit(&self, db: &Db, repo: &Repo, commit_id: &str) -> RepoResult<()> {
         let commit = try!(repo.get_commit(commit_id));
 
-        let indexed_commit = try!(Commit::new_for_git_commit(&repo.id, &commit));
+        let mut indexed_commit = try!(Commit::new_for_git_commit(&repo.id, &commit));
 
-        info!("commit {:?}", indexed_commit);
+        indexed_commit.files = Some(try!(self.commit_files(repo, &commit)));
 
-        let mut es_client = self.es_client.borrow_mut();
-        
-        let mut op = es_client.index("codelauf", "commit");
-        try!(op
-             .with_id(commit_id)
-             .with_doc(&indexed_commit)
-             .send());
+        info!("commit {} of {} changed {} files", commit_id, repo.uri, indexed_commit.files.as_ref().map(|f| f.len()).unwrap_or(0));
 
-        try!(db.mark_commit_as_indexed(&repo.id, commit_id));
+        let mut bulk = self.bulk.borrow_mut();
 
-/*        let mut diff_opts = git2::DiffOptions::new();
-        diff_opts.ignore_whitespace(true)
-            .ignore_filemode(true)
-            ;
-            
+        try!(bulk.index("commit", commit_id, &indexed_commit, BulkDone::Commit(commit_id.to_owned())));
 
-        for parent in commit.parents() {
-            let commit_tree = try!(commit.tree());
-            let parent_tree = try!(parent.tree());
-            
-            let diff = try!(git2::Diff::tree_to_tree(git_repo, Some(&parent_tree), Some(&commit_tree), Some(&mut diff_opts)));
+        bulk.maybe_flush(db, &repo.id)
+    }
+
+    /// the files a commit changed compared to its parent, with the lines it added and removed.
+    /// merge commits are left without files, as git log does by default,
+    /// because the changes they bring in are already on the merged commits
+    pub fn commit_files(&self, repo: &Repo, commit: &git2::Commit) -> RepoResult<Vec<CommitFile>> {
+        if commit.parent_count() > 1 {
+            return Ok(vec![]);
+        }
 
-            for delta in diff.deltas() {
-                info!("delta: {:?} {:?} {:?}", delta.status(), delta.old_file().path(), delta.new_file().path());
+        let git_repo = try!(repo.git_repo());
+
+        let commit_tree = try!(commit.tree());
+        let parent_tree = match commit.parents().next() {
+            Some(parent) => Some(try!(parent.tree())),
+            None => None,
+        };
+
+        let mut diff_opts = git2::DiffOptions::new();
+        diff_opts.ignore_filemode(true);
+
+        let mut diff = try!(git2::Diff::tree_to_tree(git_repo, parent_tree.as_ref(), Some(&commit_tree), Some(&mut diff_opts)));
+
+        let mut find_opts = git2::DiffFindOptions::new();
+        find_opts.renames(true).copies(true);
+
+        try!(diff.find_similar(Some(&mut find_opts)));
+
+        let mut files = vec![];
+        let mut file_indexes = HashMap::new();
+
+        for delta in diff.deltas() {
+            let path = match delta.new_file().path().or(delta.old_file().path()) {
+                Some(path) => path.to_owned(),
+                None => continue,
+            };
+
+            let old_path = match delta.status() {
+                git2::Delta::Renamed | git2::Delta::Copied => delta.old_file().path().map(|p| p.to_owned()),
+                _ => None,
+            };
+
+            file_indexes.insert(path.clone(), files.len());
+            files.push(CommitFile::new(CommitFile::change_type_for_delta(delta.status()), path, old_path));
+        }
+
+        let mut total_text = 0;
+
+        try!(diff.print(git2::DiffFormat::Patch, |delta, _hunk, line| {
+            if total_text >= MAX_COMMIT_TEXT {
+                return true;
+            }
+
+            let path = delta.new_file().path().or(delta.old_file().path());
+
+            match path.and_then(|p| file_indexes.get(p)) {
+                Some(&i) => {
+                    total_text += files[i].add_line(line.origin(), line.content());
+                },
+                None => {}
             }
+
+            true
+        }));
+
+        if total_text >= MAX_COMMIT_TEXT {
+            info!("only indexing the first {} bytes of changes in commit {}", MAX_COMMIT_TEXT, commit.id());
         }
-        */
-        Ok(())
+
+        for file in files.iter_mut() {
+            file.keywords = file.text.as_ref()
+                .and_then(|text| self.analysers.analyse(&file.path, text))
+                .map(|analysis| analysis.keywords());
+        }
+
+        Ok(files)
     }
 
     pub fn datetime_convert_git_to_chrono(git_time: &git2::Time) -> DateTime<offset::fixed::FixedOffset> {
@@ -16,6 +16,8 @@ extern crate rs_es;
 extern crate url;
 extern crate rustc_serialize;
 extern crate encoding;
+extern crate hyper;
+extern crate regex;
 
 pub mod db;
 pub mod config;
@@ -24,3 +26,14 @@ pub mod result;
 pub mod repo;
 pub mod models;
 pub mod index;
+pub mod worker;
+pub mod zk;
+pub mod progress;
+pub mod bulk;
+pub mod es_schema;
+pub mod es_query;
+pub mod glob;
+pub mod search;
+pub mod server;
+pub mod analyser;
+pub mod credentials;
@@ -14,7 +14,7 @@ fn run() -> RepoResult<()> {
     
     let args = config::parse_args();
 
-    let config = config::get_config(&args).unwrap();
+    let config = try!(config::get_config(&args));
     println!("using config:\n {:?}", config);
 
     
@@ -28,6 +28,15 @@ fn run() -> RepoResult<()> {
         Some("fetch") => {
             commands::fetch_repo(&config)
         },
+        Some("recreate") => {
+            commands::recreate(&config)
+        },
+        Some("es-init") => {
+            commands::es_init(&config)
+        },
+        Some("search") => {
+            commands::search(&config)
+        },
         Some("sync") => {
             commands::run_sync(&config)
         },
@@ -7,6 +7,7 @@ use result::*;
 pub enum CommitState {
     Indexed,
     NotIndexed,
+    Orphaned, // no longer on any tracked branch, so needs removing from the index
 }
 
 impl FromStr for CommitState {
@@ -15,6 +16,7 @@ impl FromStr for CommitState {
         match s {
             "Indexed" => Ok(CommitState::Indexed),
             "NotIndexed" => Ok(CommitState::NotIndexed),
+            "Orphaned" => Ok(CommitState::Orphaned),
             _ => Err(RepoError::EnumParseError(s.to_string()))
         }
     }
@@ -25,6 +27,7 @@ impl ToString for CommitState {
         match *self {
             CommitState::Indexed => "Indexed".to_string(),
             CommitState::NotIndexed => "NotIndexed".to_string(),
+            CommitState::Orphaned => "Orphaned".to_string(),
         }
     }
 }
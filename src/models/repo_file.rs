@@ -9,18 +9,24 @@ pub struct RepoFile {
     pub repo_id: String,
     pub branch: String,
     pub path: PathBuf,
-    pub changed_commit_id: String,
+    pub changed_commit_id: Option<String>, // None if the file has been deleted
     pub indexed_commit_id: Option<String>,
+    pub blob_id: Option<String>, // the file's content at changed_commit_id
+    pub indexed_blob_id: Option<String>, // the content last indexed for this branch
+    pub indexed_symbol_count: Option<usize>, // symbol documents last indexed for this branch. None if never recorded
 }
 
 impl RepoFile {
-    pub fn new(repo_id: String, branch: String, path: PathBuf, changed_commit_id: String, indexed_commit_id: Option<String>) -> RepoFile {
+    pub fn new(repo_id: String, branch: String, path: PathBuf, changed_commit_id: Option<String>, indexed_commit_id: Option<String>) -> RepoFile {
         RepoFile {
             repo_id: repo_id,
             branch: branch,
             path: path,
             changed_commit_id: changed_commit_id,
             indexed_commit_id: indexed_commit_id,
+            blob_id: None,
+            indexed_blob_id: None,
+            indexed_symbol_count: None,
         }
     }
     
@@ -31,6 +37,9 @@ impl RepoFile {
             path: types::path_buf_from_bytes_vec(row0.get(2)),
             changed_commit_id: row0.get(3),
             indexed_commit_id: row0.get(4),
+            blob_id: row0.get(5),
+            indexed_blob_id: row0.get(6),
+            indexed_symbol_count: row0.get::<Option<i64>>(7).map(|n| n as usize),
         })
     }
 }
@@ -62,3 +71,34 @@ impl SqliteMigration for CreateFilesTable {
         conn.execute("DROP TABLE files;", &[]).map(|_| ())
     }
 }
+
+pub struct AddFilesBlobIds;
+migration!(AddFilesBlobIds, 5, "add blob ids to files table");
+
+impl SqliteMigration for AddFilesBlobIds {
+    fn up(&self, conn: &SqliteConnection) -> SqliteResult<()> {
+        Ok(())
+            .and(conn.execute("ALTER TABLE files ADD COLUMN blob_id TEXT", &[]))
+            .and(conn.execute("ALTER TABLE files ADD COLUMN indexed_blob_id TEXT", &[]))
+            .map(|_| (()))
+    }
+
+    fn down(&self, _conn: &SqliteConnection) -> SqliteResult<()> {
+        // sqlite can't drop columns, and the extra ones are harmless
+        Ok(())
+    }
+}
+
+pub struct AddFilesSymbolCounts;
+migration!(AddFilesSymbolCounts, 8, "add symbol counts to files table");
+
+impl SqliteMigration for AddFilesSymbolCounts {
+    fn up(&self, conn: &SqliteConnection) -> SqliteResult<()> {
+        conn.execute("ALTER TABLE files ADD COLUMN indexed_symbol_count INTEGER", &[]).map(|_| ())
+    }
+
+    fn down(&self, _conn: &SqliteConnection) -> SqliteResult<()> {
+        // sqlite can't drop columns, and the extra one is harmless
+        Ok(())
+    }
+}
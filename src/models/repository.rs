@@ -17,6 +17,8 @@ pub struct Repository {
     pub added_datetime: Option<Timespec>,
     pub fetched_datetime: Option<Timespec>,
     pub indexed_datetime: Option<Timespec>,
+    pub tag_patterns: Vec<String>, // globs of tags to index alongside the branches, e.g. v*
+    pub branch_patterns: Vec<String>, // globs of remote branches to track, or all
 }
 
 impl Repository {
@@ -29,11 +31,15 @@ impl Repository {
             added_datetime: Some(time::get_time()),
             fetched_datetime: None,
             indexed_datetime: None,
+            tag_patterns: vec![],
+            branch_patterns: vec![],
         }
     }
     
     pub fn new_from_sql_row(row0: &SqliteRow) -> RepoResult<Repository> {
         let sync_state: String = row0.get(3);
+        let tag_patterns: Option<String> = row0.get(7);
+        let branch_patterns: Option<String> = row0.get(8);
 
         Ok(Repository {
             id: row0.get(0),
@@ -43,10 +49,17 @@ impl Repository {
             added_datetime: row0.get(4),
             fetched_datetime: row0.get(5),
             indexed_datetime: row0.get(6),
+            tag_patterns: split_patterns(tag_patterns),
+            branch_patterns: split_patterns(branch_patterns),
         })
     }
 }
 
+/// lists of patterns are stored one per line
+fn split_patterns(patterns: Option<String>) -> Vec<String> {
+    patterns.map(|p| p.lines().filter(|l| !l.is_empty()).map(|l| l.to_owned()).collect()).unwrap_or(vec![])
+}
+
 pub struct CreateRepositoriesTable;
 migration!(CreateRepositoriesTable, 1, "create repositories table");
 
@@ -80,3 +93,31 @@ impl SqliteMigration for CreateRepositoriesTable {
         conn.execute("DROP TABLE repositories;", &[]).map(|_| ())
     }
 }
+
+pub struct AddRepositoriesTagPatterns;
+migration!(AddRepositoriesTagPatterns, 6, "add tag patterns to repositories table");
+
+impl SqliteMigration for AddRepositoriesTagPatterns {
+    fn up(&self, conn: &SqliteConnection) -> SqliteResult<()> {
+        conn.execute("ALTER TABLE repositories ADD COLUMN tag_patterns TEXT", &[]).map(|_| ())
+    }
+
+    fn down(&self, _conn: &SqliteConnection) -> SqliteResult<()> {
+        // sqlite can't drop columns, and the extra one is harmless
+        Ok(())
+    }
+}
+
+pub struct AddRepositoriesBranchPatterns;
+migration!(AddRepositoriesBranchPatterns, 7, "add branch patterns to repositories table");
+
+impl SqliteMigration for AddRepositoriesBranchPatterns {
+    fn up(&self, conn: &SqliteConnection) -> SqliteResult<()> {
+        conn.execute("ALTER TABLE repositories ADD COLUMN branch_patterns TEXT", &[]).map(|_| ())
+    }
+
+    fn down(&self, _conn: &SqliteConnection) -> SqliteResult<()> {
+        // sqlite can't drop columns, and the extra one is harmless
+        Ok(())
+    }
+}
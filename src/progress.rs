@@ -0,0 +1,23 @@
+use repo::SyncState;
+
+/// receives progress updates from the long running parts of a sync,
+/// e.g. so they can be published to zookeeper for the web frontend
+pub trait Progress {
+    fn report(&self, repo_id: &str, status: SyncState, done: usize, total: usize);
+}
+
+/// discards progress updates. used when running one-off commands
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn report(&self, _repo_id: &str, _status: SyncState, _done: usize, _total: usize) {
+    }
+}
+
+pub fn percentage(done: usize, total: usize) -> u32 {
+    if total == 0 || done >= total {
+        100
+    } else {
+        (done * 100 / total) as u32
+    }
+}
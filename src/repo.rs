@@ -1,20 +1,163 @@
 use std::rc::Rc;
+use std::collections::HashSet;
 use std::path::{PathBuf,Path};
 use std::str;
 use std::str::FromStr;
 use std::fs;
 use std::fmt;
 use git2;
+use time;
 use sha1::Sha1;
 use super::config::{Config,RepoLocation};
 use super::result::*;
 use super::db;
+use super::progress::{Progress,NoProgress};
+use super::glob::glob_match;
+use super::credentials::CredentialConfig;
+
+/// tags are tracked in the branches table alongside branches, under names like tags/v1.0
+pub const TAG_PREFIX: &'static str = "tags/";
+
+/// the tag name if a tracked ref name is a tag
+pub fn tag_name(ref_name: &str) -> Option<&str> {
+    if ref_name.starts_with(TAG_PREFIX) {
+        Some(&ref_name[TAG_PREFIX.len()..])
+    } else {
+        None
+    }
+}
+
+/// the revwalk iterator just stops if an object is missing, so check the walk reached its boundary:
+/// each tip and the parents of each commit walked must have been walked too, or be hidden
+fn check_walk_complete(git_repo: &git2::Repository, tips: &[git2::Oid], hidden: &[git2::Oid], walked: &[git2::Oid]) -> RepoResult<()> {
+    let walked_set: HashSet<git2::Oid> = walked.iter().cloned().collect();
+
+    let is_hidden = |oid: git2::Oid| {
+        hidden.iter().any(|h| *h == oid || git_repo.merge_base(*h, oid).map(|base| base == oid).unwrap_or(false))
+    };
+
+    for tip in tips {
+        if !walked_set.contains(tip) && !is_hidden(*tip) {
+            return Err(RepoError::CorruptedRepo(format!("history of commit {} can't be walked", tip)));
+        }
+    }
+
+    for oid in walked {
+        let commit = match git_repo.find_commit(*oid) {
+            Ok(commit) => commit,
+            Err(err) => return Err(RepoError::CorruptedRepo(format!("commit {} is missing: {}", oid, err))),
+        };
+
+        for parent_id in commit.parent_ids() {
+            if !walked_set.contains(&parent_id) && !is_hidden(parent_id) {
+                return Err(RepoError::CorruptedRepo(format!("parent {} of commit {} is missing", parent_id, oid)));
+            }
+        }
+    }
+
+    Ok(())
+}
 
-#[derive(Debug,Copy,Clone)]
+fn path_exists(path: &Path) -> bool {
+    fs::metadata(path).is_ok()
+}
+
+/// branches can be given as globs like release/*, or all, to track every matching remote branch
+pub fn is_branch_pattern(branch: &str) -> bool {
+    branch == "all" || branch.contains('*') || branch.contains('?')
+}
+
+pub fn branch_pattern_matches(pattern: &str, branch: &str) -> bool {
+    pattern == "all" || glob_match(pattern, branch)
+}
+
+/// states a repo moves through while being synced.
+/// they're persisted in the repositories table so that after a crash
+/// we know which phase failed and can resume from there.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
 pub enum SyncState {
     NotCloned,
+    Started,
+    StartFail,
+    Cloning,
+    CloneFail,
     Cloned,
+    Fetching,
+    FetchFail,
+    Fetched,
+    IndexingCommits,
+    IndexCommitsFail,
+    IndexedCommits,
+    IndexingFiles,
+    IndexFilesFail,
+    IndexedFiles,
     Corrupted,
+    RemoteRepaired,
+}
+
+impl SyncState {
+    pub fn can_transition_to(&self, next: SyncState) -> bool {
+        use self::SyncState::*;
+        
+        match (*self, next) {
+            // the clone can go missing or turn out to be broken at any time
+            (_, NotCloned) | (_, Corrupted) | (_, StartFail) => true,
+
+            // or to have the wrong origin when it's opened, which is fixed by fetching again
+            (_, RemoteRepaired) => true,
+            (RemoteRepaired, Fetching) => true,
+            
+            (IndexedFiles, Started) => true,
+            
+            (NotCloned, Cloning) | (Cloning, Cloning) | (CloneFail, Cloning) | (Corrupted, Cloning) => true,
+            (Cloning, Cloned) | (Cloning, CloneFail) => true,
+
+            (Started, Fetching) | (StartFail, Fetching) | (Cloned, Fetching) |
+            (Fetching, Fetching) | (FetchFail, Fetching) | (Fetched, Fetching) |
+            (IndexingCommits, Fetching) | (IndexCommitsFail, Fetching) | (IndexedFiles, Fetching) => true,
+            (Fetching, Fetched) | (Fetching, FetchFail) => true,
+
+            (Cloned, IndexingCommits) | (Fetched, IndexingCommits) |
+            (IndexingCommits, IndexingCommits) | (IndexCommitsFail, IndexingCommits) => true,
+            (IndexingCommits, IndexedCommits) | (IndexingCommits, IndexCommitsFail) => true,
+
+            (IndexedCommits, IndexingFiles) | (IndexingFiles, IndexingFiles) | (IndexFilesFail, IndexingFiles) => true,
+            (IndexingFiles, IndexedFiles) | (IndexingFiles, IndexFilesFail) => true,
+
+            _ => false
+        }
+    }
+
+    pub fn is_cloned(&self) -> bool {
+        match *self {
+            SyncState::NotCloned | SyncState::Cloning | SyncState::CloneFail | SyncState::Corrupted => false,
+            _ => true
+        }
+    }
+
+    /// true if all commits found by the last revwalk are in the index,
+    /// so a resumed sync can go straight to indexing files
+    pub fn commits_indexed(&self) -> bool {
+        match *self {
+            SyncState::IndexedCommits | SyncState::IndexingFiles | SyncState::IndexFilesFail => true,
+            _ => false
+        }
+    }
+
+    /// true if the last fetch finished and its commits are in the work table
+    pub fn commits_fetched(&self) -> bool {
+        match *self {
+            SyncState::Fetched | SyncState::IndexingCommits | SyncState::IndexCommitsFail => true,
+            _ => false
+        }
+    }
+
+    pub fn is_in_progress(&self) -> bool {
+        match *self {
+            SyncState::Cloning | SyncState::Fetching | SyncState::IndexingCommits | SyncState::IndexingFiles => true,
+            _ => false
+        }
+    }
 }
 
 impl FromStr for SyncState {
@@ -22,8 +165,22 @@ impl FromStr for SyncState {
     fn from_str(s: &str) -> Result<SyncState, Self::Err> {
         match s {
             "NotCloned" => Ok(SyncState::NotCloned),
+            "Started" => Ok(SyncState::Started),
+            "StartFail" => Ok(SyncState::StartFail),
+            "Cloning" => Ok(SyncState::Cloning),
+            "CloneFail" => Ok(SyncState::CloneFail),
             "Cloned" => Ok(SyncState::Cloned),
+            "Fetching" => Ok(SyncState::Fetching),
+            "FetchFail" => Ok(SyncState::FetchFail),
+            "Fetched" => Ok(SyncState::Fetched),
+            "IndexingCommits" => Ok(SyncState::IndexingCommits),
+            "IndexCommitsFail" => Ok(SyncState::IndexCommitsFail),
+            "IndexedCommits" => Ok(SyncState::IndexedCommits),
+            "IndexingFiles" => Ok(SyncState::IndexingFiles),
+            "IndexFilesFail" => Ok(SyncState::IndexFilesFail),
+            "IndexedFiles" => Ok(SyncState::IndexedFiles),
             "Corrupted" => Ok(SyncState::Corrupted),
+            "RemoteRepaired" => Ok(SyncState::RemoteRepaired),
             _ => Err(RepoError::EnumParseError(s.to_string()))
         }
     }
@@ -33,8 +190,22 @@ impl ToString for SyncState {
     fn to_string(&self) -> String {
         match *self {
             SyncState::NotCloned => "NotCloned".to_string(),
+            SyncState::Started => "Started".to_string(),
+            SyncState::StartFail => "StartFail".to_string(),
+            SyncState::Cloning => "Cloning".to_string(),
+            SyncState::CloneFail => "CloneFail".to_string(),
             SyncState::Cloned => "Cloned".to_string(),
+            SyncState::Fetching => "Fetching".to_string(),
+            SyncState::FetchFail => "FetchFail".to_string(),
+            SyncState::Fetched => "Fetched".to_string(),
+            SyncState::IndexingCommits => "IndexingCommits".to_string(),
+            SyncState::IndexCommitsFail => "IndexCommitsFail".to_string(),
+            SyncState::IndexedCommits => "IndexedCommits".to_string(),
+            SyncState::IndexingFiles => "IndexingFiles".to_string(),
+            SyncState::IndexFilesFail => "IndexFilesFail".to_string(),
+            SyncState::IndexedFiles => "IndexedFiles".to_string(),
             SyncState::Corrupted => "Corrupted".to_string(),
+            SyncState::RemoteRepaired => "RemoteRepaired".to_string(),
         }
     }
 }
@@ -120,8 +291,13 @@ pub struct Repo {
     pub path: PathBuf,
     pub uri: String,
     pub branches: Vec<Branch>,
+    pub branch_patterns: Vec<String>,
+    pub tag_patterns: Vec<String>,
+    pub retired_branches: Vec<String>, // tracked refs that have gone, whose documents need removing
+    pub credentials: Option<CredentialConfig>,
     pub sync_state: SyncState,
     pub git_repo: Option<Rc<git2::Repository>>,
+    pub progress: Rc<Progress>,
 }
 
 impl fmt::Debug for Repo {
@@ -135,9 +311,27 @@ impl Repo {
         let repo_loc = try!(config.repo_location.as_ref().ok_or(RepoError::NoRemote));
 
         let uri = try!(repo_loc.remote.as_ref().ok_or(RepoError::NoRemote));
-        let branches = repo_loc.branches.iter().map(|b| Branch::new(b.clone(), None) ).collect();
+        let branches = repo_loc.branches.iter()
+            .filter(|b| !is_branch_pattern(b))
+            .map(|b| Branch::new(b.clone(), None) )
+            .collect();
+
+        let mut repo = Repo::new(try!(Repo::get_repo_path(config, repo_loc)), uri.clone(), branches, SyncState::NotCloned);
+        repo.branch_patterns = repo_loc.branches.iter().filter(|b| is_branch_pattern(b)).cloned().collect();
+        repo.tag_patterns = repo_loc.tags.clone();
+        repo.use_credentials(config);
 
-        Ok(Repo::new(try!(Repo::get_repo_path(config, repo_loc)), uri.clone(), branches, SyncState::NotCloned))
+        Ok(repo)
+    }
+    
+    pub fn new_for_db_repo(db_repo: &db::Repository, db_branches: &Vec<db::RepoBranch>) -> Repo {
+        let branches = db_branches.iter().map(|b| Branch::new(b.name.clone(), b.indexed_commit_id.clone()) ).collect();
+
+        let mut repo = Repo::new(db_repo.path.clone(), db_repo.uri.clone(), branches, db_repo.sync_state);
+        repo.branch_patterns = db_repo.branch_patterns.clone();
+        repo.tag_patterns = db_repo.tag_patterns.clone();
+
+        repo
     }
     
     pub fn new(path: PathBuf, uri: String, branches: Vec<Branch>, sync_state: SyncState) -> Repo {
@@ -146,20 +340,40 @@ impl Repo {
             path: path,
             uri: uri,
             branches: branches,
+            branch_patterns: vec![],
+            tag_patterns: vec![],
+            retired_branches: vec![],
+            credentials: None,
             sync_state: sync_state,
             git_repo: None,
+            progress: Rc::new(NoProgress),
         }
     }
 
-    fn new_git_callbacks<'a>() -> git2::RemoteCallbacks<'a> {
+    /// pick the configured credentials for the remote, if there are any
+    pub fn use_credentials(&mut self, config: &Config) {
+        self.credentials = CredentialConfig::find_for_remote(&config.credentials, &self.uri).cloned();
+    }
+
+    fn new_git_callbacks<'a>(&'a self, status: SyncState) -> git2::RemoteCallbacks<'a> {
         let mut grcs = git2::RemoteCallbacks::<'a>::new();
 
+        let mut tried = git2::CredentialType::empty();
+
+        grcs.credentials(move |url, username_from_url, allowed| {
+            match self.credentials {
+                Some(ref credentials) => credentials.git_credentials(url, username_from_url, allowed, &mut tried),
+                None => Err(git2::Error::from_str(&format!("no credentials configured for {}", url)))
+            }
+        });
+
         grcs
-            .transfer_progress(|prog| {
+            .transfer_progress(move |prog| {
                 info!("total: {} received: {} indexed: {}",
                       prog.total_objects(),
                       prog.received_objects(),
                       prog.indexed_objects());
+                self.progress.report(&self.id, status, prog.received_objects(), prog.total_objects());
                 true
             })
             .sideband_progress(|data| {
@@ -174,24 +388,70 @@ impl Repo {
     }
 
     pub fn is_cloned(&self) -> bool {
-        match self.sync_state {
-            SyncState::NotCloned => false,
-            _ => true
-        }
+        self.sync_state.is_cloned()
     }
     
+    /// clones used to have a working tree, with the repository in .git
     pub fn dot_git_path(&self) -> PathBuf {
         self.path.join(".git")
     }
+
+    /// where a working tree clone is moved while it's converted to a bare one
+    pub fn old_work_tree_path(&self) -> PathBuf {
+        self.path.with_extension("worktree")
+    }
     
+    /// true if there's a bare clone, or a working tree clone waiting to be converted
     pub fn dot_git_exists(&self) -> bool {
-        match fs::metadata(self.dot_git_path().as_path()) {
-            Ok(_) => true,
-            Err(_) => {
-                info!("repo doesn't exist at {:?}", self.path);
-                false
+        if path_exists(&self.path.join("HEAD")) || path_exists(&self.dot_git_path()) || path_exists(&self.old_work_tree_path().join(".git")) {
+            true
+        } else {
+            info!("repo doesn't exist at {:?}", self.path);
+            false
+        }
+    }
+
+    /// turn a working tree clone into a bare one, keeping just its .git dir.
+    /// each step can be picked up again if interrupted
+    pub fn convert_to_bare(&self) -> RepoResult<()> {
+        let work_tree = self.old_work_tree_path();
+        let git_dir = work_tree.join(".git");
+
+        info!("converting clone at {:?} to a bare repository", self.path);
+
+        if path_exists(&self.dot_git_path()) {
+            if path_exists(&work_tree) {
+                try!(fs::remove_dir_all(&work_tree));
             }
+
+            try!(fs::rename(&self.path, &work_tree));
         }
+
+        if path_exists(&git_dir) {
+            {
+                let git_repo = try!(git2::Repository::open(&git_dir));
+
+                try!(try!(git_repo.config()).set_bool("core.bare", true));
+            }
+
+            try!(fs::rename(&git_dir, &self.path));
+        }
+
+        // what's left is the old working tree
+        if path_exists(&work_tree) {
+            try!(fs::remove_dir_all(&work_tree));
+        }
+
+        Ok(())
+    }
+
+    /// true if there's a working tree clone, or one that was part way through being converted
+    pub fn needs_convert_to_bare(&self) -> bool {
+        let work_tree = self.old_work_tree_path();
+
+        path_exists(&self.dot_git_path())
+            || path_exists(&work_tree.join(".git"))
+            || (path_exists(&work_tree) && path_exists(&self.path.join("HEAD")))
     }
 
     pub fn find_in_db(&self, db: &db::Db) -> RepoResult<Option<db::Repository>> {
@@ -203,7 +463,9 @@ impl Repo {
 
         let remote_uri = &self.uri;
         
-        let new_repo = db::Repository::new_from_remote(self.id.clone(), remote_uri.clone(), self.path.clone());
+        let mut new_repo = db::Repository::new_from_remote(self.id.clone(), remote_uri.clone(), self.path.clone());
+        new_repo.branch_patterns = self.branch_patterns.clone();
+        new_repo.tag_patterns = self.tag_patterns.clone();
         try!(db.insert_repo(&new_repo));
         
         info!("created db repo entry {:?}", new_repo);
@@ -239,17 +501,18 @@ impl Repo {
 
         let mut db_repo = try!(self.find_or_create_in_db(db));
 
-        match db_repo.sync_state {
-            SyncState::NotCloned => {
-                db_repo.sync_state = self.sync_state;
-            },
-            SyncState::Cloned => {
-                db_repo.sync_state = self.sync_state;
-            },
-            other_state => {
-                self.sync_state = other_state;
-            }
+        // the filesystem wins if the clone has gone missing, or was never recorded.
+        // otherwise keep the phase recorded in the db so we can resume from it.
+        // that includes a clone that was interrupted, failed or found corrupted,
+        // since its directory exists but shouldn't be trusted
+        if self.is_cloned() && db_repo.sync_state != SyncState::NotCloned {
+            self.sync_state = db_repo.sync_state;
+        } else {
+            db_repo.sync_state = self.sync_state;
         }
+
+        db_repo.branch_patterns = self.branch_patterns.clone();
+        db_repo.tag_patterns = self.tag_patterns.clone();
         
         try!(db.update_repo(&db_repo));
         
@@ -259,6 +522,10 @@ impl Repo {
     pub fn probe_fs(&mut self) -> RepoResult<()> {
         info!("probing cloned repo {}", self.uri);
 
+        if self.needs_convert_to_bare() {
+            try!(self.convert_to_bare());
+        }
+
         if !self.dot_git_exists() {
             self.set_state(SyncState::NotCloned);
             Ok(())
@@ -273,10 +540,22 @@ impl Repo {
         }
     }
 
+    /// clones are bare, since files are indexed straight from the object database.
+    /// the remote branches are kept under refs/remotes/origin as usual,
+    /// so the local branches can be moved to them after each fetch
     pub fn clone_repo(&mut self) -> RepoResult<()> {
-        self.git_repo = Some(Rc::new(try!(git2::Repository::clone(&self.uri, self.path.clone()))));
+        let git_repo = {
+            let mut fo = git2::FetchOptions::new();
+            fo.remote_callbacks(self.new_git_callbacks(SyncState::Cloning));
+
+            let mut rb = git2::build::RepoBuilder::new();
+            rb.bare(true);
+            rb.fetch_options(fo);
 
-        self.sync_state = SyncState::Cloned;
+            try!(rb.clone(&self.uri, &self.path))
+        };
+
+        self.git_repo = Some(Rc::new(git_repo));
 
         Ok(())
     }
@@ -287,8 +566,39 @@ impl Repo {
         Ok(())
     }
 
+    /// make sure origin points at the repo's uri, adding it or replacing it if not.
+    /// a replaced origin's remote branches are deleted with it, so nothing from the wrong remote is indexed.
+    /// returns true if it had to be repaired
+    pub fn repair_remote(&self) -> RepoResult<bool> {
+        let git_repo = try!(self.git_repo());
+
+        let url = match git_repo.find_remote("origin") {
+            Ok(remote) => Some(remote.url().map(|u| u.to_owned())),
+            Err(_) => None,
+        };
+
+        match url {
+            Some(Some(ref url)) if *url == self.uri => {
+                return Ok(false);
+            },
+            Some(url) => {
+                info!("origin of clone at {:?} is {:?} instead of {}. replacing it", self.path, url, self.uri);
+
+                try!(git_repo.remote_delete("origin"));
+            },
+            None => {
+                info!("clone at {:?} has no origin. adding it", self.path);
+            }
+        }
+
+        try!(git_repo.remote("origin", &self.uri));
+
+        Ok(true)
+    }
+
     fn find_or_create_git_remote<'a> (&'a self, repo: &'a git2::Repository) -> RepoResult<git2::Remote> {
-        // TODO: ensure returned remote has correct uri
+        try!(self.repair_remote());
+
         repo.find_remote("origin").map_err(|e| RepoError::GitError(e))
     }
 
@@ -296,7 +606,7 @@ impl Repo {
         let git_repo = try!(self.git_repo());
 
         let mut fo = git2::FetchOptions::new();
-        let grcs = Repo::new_git_callbacks();
+        let grcs = self.new_git_callbacks(SyncState::Fetching);
         
         fo.prune(git2::FetchPrune::On);
         fo.remote_callbacks(grcs);
@@ -304,15 +614,139 @@ impl Repo {
         let mut remote = try!(self.find_or_create_git_remote(&git_repo));
 
         info!("fetching from remote");
-        let branch_names: Vec<&str> = self.branches.iter().map(|s| &s.name[..]).collect();
-        try!(remote.fetch(&branch_names, Some(&mut fo), None));
+        let mut refspecs: Vec<&str> = self.branches.iter()
+            .filter(|b| tag_name(&b.name).is_none())
+            .map(|s| &s.name[..])
+            .collect();
+
+        // to find new branches matching the patterns, all of them have to be fetched.
+        // tags are fetched straight into refs/tags.
+        // with pruning on, refs deleted upstream disappear from the clone too
+        if !self.branch_patterns.is_empty() {
+            refspecs.push("+refs/heads/*:refs/remotes/origin/*");
+        }
+
+        if !self.tag_patterns.is_empty() {
+            refspecs.push("+refs/tags/*:refs/tags/*");
+        }
+
+        try!(remote.fetch(&refspecs, Some(&mut fo), None));
         info!("fetched.");
 
         Ok(())
     }
 
+    /// names of the tags in the clone that match any of the repo's tag patterns
+    pub fn matching_tag_names(&self) -> RepoResult<Vec<String>> {
+        let git_repo = try!(self.git_repo());
+
+        let tag_names = try!(git_repo.tag_names(None));
+
+        Ok(tag_names.iter()
+           .filter_map(|name| name)
+           .filter(|name| self.tag_patterns.iter().any(|pattern| glob_match(pattern, name)))
+           .map(|name| name.to_owned())
+           .collect())
+    }
+
+    /// names of the remote branches that match any of the repo's branch patterns
+    pub fn matching_remote_branch_names(&self) -> RepoResult<Vec<String>> {
+        let git_repo = try!(self.git_repo());
+
+        let prefix = "refs/remotes/origin/";
+
+        let mut names = vec![];
+
+        for ref_name in try!(git_repo.references_glob(&format!("{}*", prefix))).names() {
+            let name = &ref_name[prefix.len()..];
+
+            if name != "HEAD" && self.branch_patterns.iter().any(|pattern| branch_pattern_matches(pattern, name)) {
+                names.push(name.to_owned());
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// start tracking a ref found in the clone, unless it's already tracked
+    fn track_ref(&mut self, db: &db::Db, name: String) -> RepoResult<()> {
+        if self.branches.iter().any(|b| b.name == name) {
+            return Ok(());
+        }
+
+        let indexed_commit = match try!(db.find_branch(&self.id, &name)) {
+            Some(db_branch) => db_branch.indexed_commit_id,
+            None => {
+                info!("tracking new ref {} of {}", name, self.uri);
+
+                try!(db.insert_branch(&db::RepoBranch::new(self.id.clone(), name.clone(), None)));
+
+                None
+            }
+        };
+
+        self.branches.push(Branch::new(name, indexed_commit));
+
+        Ok(())
+    }
+
+    /// bring the tracked refs in line with the clone.
+    /// tags and remote branches matching the repo's patterns that aren't tracked yet
+    /// are added to the branches table. tracked tags that have gone or no longer match,
+    /// and branches matched by a pattern that have gone upstream, are retired
+    /// so their documents are removed when the files are next indexed.
+    /// branches named explicitly are left alone
+    pub fn track_refs(&mut self, db: &db::Db) -> RepoResult<()> {
+        let tags = try!(self.matching_tag_names());
+        let remote_branches = try!(self.matching_remote_branch_names());
+
+        for tag in tags.iter() {
+            try!(self.track_ref(db, format!("{}{}", TAG_PREFIX, tag)));
+        }
+
+        for branch in remote_branches.iter() {
+            if !self.branches.iter().any(|b| b.name == *branch) {
+                // the local branch is what gets indexed
+                try!(self.repoint_branch_to_origin(branch));
+            }
+
+            try!(self.track_ref(db, branch.clone()));
+        }
+
+        for db_branch in try!(db.find_branches(&self.id)) {
+            let gone = match tag_name(&db_branch.name) {
+                Some(tag) => !tags.iter().any(|t| t == tag),
+                None => {
+                    self.branch_patterns.iter().any(|pattern| branch_pattern_matches(pattern, &db_branch.name)) &&
+                        !remote_branches.contains(&db_branch.name)
+                }
+            };
+
+            if gone && !self.retired_branches.contains(&db_branch.name) {
+                info!("{} of {} has gone. removing it from the index", db_branch.name, self.uri);
+
+                self.retired_branches.push(db_branch.name);
+            }
+        }
+
+        let retired = self.retired_branches.clone();
+        self.branches.retain(|b| !retired.contains(&b.name));
+
+        Ok(())
+    }
+
+    /// the full ref name of a tracked branch or tag
     pub fn find_branch(&self, git_repo: &git2::Repository, branch_name: &str) -> RepoResult<String> {
         info!("finding branch {}", branch_name);
+
+        match tag_name(branch_name) {
+            Some(tag) => {
+                let tag_ref = try!(git_repo.find_reference(&format!("refs/tags/{}", tag)));
+
+                return tag_ref.name().ok_or(RepoError::StringUnicodeError).map(|s| s.to_string());
+            },
+            None => {}
+        }
         
         let branch = try!(git_repo.find_branch(branch_name, git2::BranchType::Local));
         
@@ -323,6 +757,7 @@ impl Repo {
         Ok(branch_fullname)
     }
 
+    /// clones are bare, so this only moves HEAD. there's no working tree to update
     pub fn checkout_branch(&mut self, branch_name: &str) -> RepoResult<()> {
         let git_repo = try!(self.git_repo());
 
@@ -330,12 +765,6 @@ impl Repo {
 
         info!("setting head to {}", branch_fullname);
         try!(git_repo.set_head(&branch_fullname));
-        
-        let mut cb = git2::build::CheckoutBuilder::new();
-        cb.force();
-
-        info!("checkout {}", branch_name);
-        try!(git_repo.checkout_head(Some(&mut cb)).map_err(|e| RepoError::GitError(e)));
 
         Ok(())
     }
@@ -350,33 +779,119 @@ impl Repo {
         let remote_ref = format!("refs/remotes/{}/{}", remote_name, branch_name);
         let local_ref = format!("refs/heads/{}", branch_name);
 
-        info!("getting commit id for local branch {}", local_ref);
-        let local_oid = try!(git_repo.refname_to_id(&local_ref));
-
         info!("getting commit id for remote branch {}", remote_ref);
         let remote_oid = try!(git_repo.refname_to_id(&remote_ref));
 
-        let reflog_msg = format!("update-ref: moving {} from {} to {}", local_ref, local_oid, remote_oid);
+        // a newly discovered branch won't have a local branch yet
+        info!("getting commit id for local branch {}", local_ref);
+        let reflog_msg = match git_repo.refname_to_id(&local_ref) {
+            Ok(local_oid) => format!("update-ref: moving {} from {} to {}", local_ref, local_oid, remote_oid),
+            Err(_) => format!("update-ref: creating {} at {}", local_ref, remote_oid),
+        };
+
         try!(git_repo.reference(&local_ref, remote_oid, true, &reflog_msg));
 
         Ok(())
     }
 
-    pub fn pull_repo(&self) -> RepoResult<()> {
-        try!(self.fetch_repo());
+    /// tags are fetched straight into refs/tags, so their remote commit is the local one
+    pub fn remote_branch_commit_id(&self, branch_name: &str) -> RepoResult<git2::Oid> {
+        if tag_name(branch_name).is_some() {
+            return self.branch_commit_id(branch_name);
+        }
+
+        let git_repo = try!(self.git_repo());
+
+        let remote_ref = format!("refs/remotes/origin/{}", branch_name);
+
+        let id = try!(git_repo.refname_to_id(&remote_ref));
+
+        Ok(id)
+    }
+
+    /// if upstream has been force pushed, commits we've indexed may not be on any tracked branch anymore.
+    /// this finds them by walking back from each diverged indexed commit,
+    /// hiding everything reachable from the new remote branch tips,
+    /// and records them in the commits work table so they're removed from the index.
+    /// must be called after fetching and before moving the local branches.
+    pub fn record_orphaned_commits(&self, db: &db::Db) -> RepoResult<()> {
+        let git_repo = try!(self.git_repo());
+
+        let mut revwalk = try!(git_repo.revwalk());
+        let mut diverged = false;
 
         for branch in &self.branches {
-            try!(self.repoint_branch_to_origin(&branch.name));
+            match branch.indexed_commit {
+                Some(ref indexed_commit) => {
+                    let indexed_oid = try!(git2::Oid::from_str(indexed_commit));
+                    let remote_oid = try!(self.remote_branch_commit_id(&branch.name));
+
+                    let fast_forward = match git_repo.merge_base(indexed_oid, remote_oid) {
+                        Ok(base) => base == indexed_oid,
+                        Err(_) => false,
+                    };
+
+                    if !fast_forward {
+                        info!("branch {} has diverged from indexed commit {}", branch.name, indexed_commit);
+
+                        try!(revwalk.push(indexed_oid));
+                        diverged = true;
+                    }
+                },
+                None => {}
+            }
+        }
+
+        if !diverged {
+            return Ok(());
         }
+
+        for branch in &self.branches {
+            try!(revwalk.hide(try!(self.remote_branch_commit_id(&branch.name))));
+        }
+
+        for oid in revwalk {
+            info!("orphaned commit {:?}", oid);
+
+            try!(db.mark_commit_as_orphaned(&self.id, &format!("{}", oid)));
+        }
+
+        Ok(())
+    }
+
+    pub fn pull_repo(&mut self, db: &db::Db) -> RepoResult<()> {
+        try!(self.fetch_repo());
+
+        try!(self.track_refs(db));
+
+        // newly tracked branches may have been given indexed commits that were since rewritten upstream
+        try!(self.forget_missing_indexed_commits(db));
+
+        try!(self.record_orphaned_commits(db));
+
+        try!(self.repoint_branches_to_origin());
         
         //try!(self.checkout_head());
 
         Ok(())
     }
 
-    pub fn revwalk_add_branch(&self, git_repo: &git2::Repository, revwalk: &mut git2::Revwalk, branch_name: &str, indexed_commit: &Option<String>) -> RepoResult<()> {
+    /// a fresh clone only has a local branch for the remote's HEAD, so this also creates the others
+    pub fn repoint_branches_to_origin(&self) -> RepoResult<()> {
+        for branch in self.branches.iter().filter(|b| tag_name(&b.name).is_none()) {
+            try!(self.repoint_branch_to_origin(&branch.name));
+        }
+
+        Ok(())
+    }
+
+    /// pushes the branch onto the walk, hiding what was already indexed.
+    /// returns the branch commit and the hidden merge bases, which bound the walk
+    pub fn revwalk_add_branch(&self, git_repo: &git2::Repository, revwalk: &mut git2::Revwalk, branch_name: &str, indexed_commit: &Option<String>) -> RepoResult<(git2::Oid, Vec<git2::Oid>)> {
 
         let branch_commit = try!(self.branch_commit_id(branch_name));
+
+        let mut hidden = vec![];
         
         if indexed_commit.is_some() {
             let indexed_commit_id = try!(git_repo.revparse_single(indexed_commit.as_ref().unwrap())).id();
@@ -385,12 +900,14 @@ impl Repo {
             
             for base in bases.iter() {
                 try!(revwalk.hide(*base));
+
+                hidden.push(*base);
             }
         }
         
         try!(revwalk.push(branch_commit));
 
-        Ok(())
+        Ok((branch_commit, hidden))
     }
 
     /// walks commits from current head to merge-base of self.commit if any
@@ -401,22 +918,117 @@ impl Repo {
 
         let mut revwalk = try!(git_repo.revwalk());
 
+        let mut tips = vec![];
+        let mut hidden = vec![];
+
         if self.branches.is_empty() {
-            try!(self.revwalk_add_branch(&git_repo, &mut revwalk, "master", &None));
+            let (tip, bases) = try!(self.revwalk_add_branch(&git_repo, &mut revwalk, "master", &None));
+            tips.push(tip);
+            hidden.extend(bases);
         } else {
             for branch in &self.branches {
-                try!(self.revwalk_add_branch(&git_repo, &mut revwalk, &branch.name, &branch.indexed_commit));
+                let (tip, bases) = try!(self.revwalk_add_branch(&git_repo, &mut revwalk, &branch.name, &branch.indexed_commit));
+                tips.push(tip);
+                hidden.extend(bases);
             }
         }
 
+        let walked: Vec<git2::Oid> = revwalk.collect();
+
+        try!(check_walk_complete(git_repo, &tips, &hidden, &walked));
+
         info!("commit history:");
-        for oid in revwalk {
+        for oid in walked {
             try!(self.add_commit(db, &oid));
         }
         
         Ok(())
     }
 
+    /// names of the branches whose indexed commit isn't in the repo
+    pub fn find_missing_indexed_commits(&self) -> RepoResult<Vec<String>> {
+        let git_repo = try!(self.git_repo());
+
+        Ok(self.branches.iter()
+           .filter(|b| {
+               b.indexed_commit.as_ref().map(|c| {
+                   git2::Oid::from_str(c).and_then(|oid| git_repo.find_commit(oid)).is_err()
+               }).unwrap_or(false)
+           })
+           .map(|b| b.name.clone())
+           .collect())
+    }
+
+    /// an existing clone should still have every commit that's been indexed,
+    /// since fetching never removes objects. if it doesn't, it's been damaged.
+    /// branches without a ref in the clone haven't been fetched into it yet,
+    /// so their indexed commits came from zookeeper and are checked after the fetch
+    pub fn check_indexed_commits_present(&self) -> RepoResult<()> {
+        let git_repo = try!(self.git_repo());
+
+        let missing: Vec<String> = try!(self.find_missing_indexed_commits())
+            .into_iter()
+            .filter(|name| self.find_branch(git_repo, name).is_ok())
+            .collect();
+
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(RepoError::CorruptedRepo(format!("indexed commits of {:?} are missing", missing)))
+        }
+    }
+
+    /// where a corrupted clone is moved, so it can be looked at later
+    pub fn corrupted_path(&self) -> PathBuf {
+        self.path.with_extension(format!("corrupted-{}", time::get_time().sec))
+    }
+
+    /// move a corrupted clone out of the way so a fresh one can be made
+    pub fn move_aside(&mut self) -> RepoResult<()> {
+        self.git_repo = None;
+
+        if path_exists(&self.path) {
+            let corrupted_path = self.corrupted_path();
+
+            info!("moving corrupted clone at {:?} to {:?}", self.path, corrupted_path);
+
+            try!(fs::rename(&self.path, &corrupted_path));
+        }
+
+        Ok(())
+    }
+
+    /// delete the clone, so the next sync makes a fresh one
+    pub fn delete_clone(&mut self) -> RepoResult<()> {
+        self.git_repo = None;
+
+        if path_exists(&self.path) {
+            info!("removing clone at {:?}", self.path);
+
+            try!(fs::remove_dir_all(&self.path));
+        }
+
+        Ok(())
+    }
+
+    /// clear any indexed commit ids that aren't in the repo, e.g. ones restored from zookeeper
+    /// that were since rewritten upstream. those branches will be indexed from scratch.
+    pub fn forget_missing_indexed_commits(&mut self, db: &db::Db) -> RepoResult<()> {
+        let missing = try!(self.find_missing_indexed_commits());
+
+        for branch in self.branches.iter_mut() {
+            if missing.contains(&branch.name) {
+                info!("indexed commit {:?} of branch {} not found in repo. clearing it", branch.indexed_commit, branch.name);
+
+                branch.indexed_commit = None;
+
+                try!(db.clear_branch_indexed_commit(&self.id, &branch.name));
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn get_commit<'a>(&'a self, commit_id: &str) -> RepoResult<git2::Commit<'a> > {
         info!("getting commit {:?}", commit_id);
         let git_repo = try!(self.git_repo());
@@ -428,12 +1040,59 @@ impl Repo {
         Ok(commit)
     }
 
-    //todo what's this for?
-    pub fn treediff(&self, _db: &db::Db, _indexed_commit_id: &str, _branch_commit_id: &str) -> RepoResult<()> {
+    /// adds to the files table the files added or modified on a branch since it was last indexed,
+    /// and records deleted files (and the old side of renames) with no changed commit id
+    /// so they're removed from the index.
+    pub fn treediff(&self, db: &db::Db, branch: &str, indexed_commit_id: &str, branch_commit_id: &str) -> RepoResult<()> {
+        let git_repo = try!(self.git_repo());
+
+        let old_tree = try!(try!(self.get_commit(indexed_commit_id)).tree());
+        let new_tree = try!(try!(self.get_commit(branch_commit_id)).tree());
+
+        let mut diff_opts = git2::DiffOptions::new();
+        diff_opts.ignore_filemode(true);
+
+        let mut diff = try!(git2::Diff::tree_to_tree(git_repo, Some(&old_tree), Some(&new_tree), Some(&mut diff_opts)));
+
+        // so that renames show up as Delta::Renamed instead of an add and a delete
+        try!(diff.find_similar(None));
+
+        for delta in diff.deltas() {
+            let old_file = delta.old_file();
+            let new_file = delta.new_file();
+
+            info!("delta: {:?} {:?} {:?}", delta.status(), old_file.path(), new_file.path());
+
+            match delta.status() {
+                git2::Delta::Deleted | git2::Delta::Renamed => {
+                    match old_file.path() {
+                        Some(old_path) => try!(db.upsert_file(&self.id, branch, old_path, None, None)),
+                        None => {}
+                    }
+                },
+                _ => {}
+            }
+
+            match delta.status() {
+                git2::Delta::Deleted => {},
+                _ => {
+                    match new_file.path() {
+                        Some(new_path) if !new_file.id().is_zero() => {
+                            let blob_id = format!("{}", new_file.id());
+
+                            try!(db.upsert_file(&self.id, branch, new_path, Some(branch_commit_id), Some(&blob_id)));
+                        },
+                        _ => {}
+                    }
+                }
+            }
+        }
+
         Ok(())
     }
 
-    //todo what's this for?
+    /// fill the files work table for each branch:
+    /// just the changes since the indexed commit if there is one, otherwise every file
     pub fn treewalks(&self, db: &db::Db) -> RepoResult<()> {
         for branch in self.branches.iter() {
             let branch_commit_id = try!(self.branch_commit_id(&branch.name));
@@ -446,7 +1105,7 @@ impl Repo {
             if indexed_commit_id.is_some() {
                 // tree-to-tree diff it and head, adding changed files to table:
 
-                try!(self.treediff(db, indexed_commit_id.as_ref().unwrap(), &branch_commit_id_str));
+                try!(self.treediff(db, &repo_branch.name, indexed_commit_id.as_ref().unwrap(), &branch_commit_id_str));
             } else {
                 // add all files to files table
                 try!(self.treewalk(db, &repo_branch.name, &branch_commit_id_str));
@@ -475,7 +1134,9 @@ impl Repo {
                     //todo get contents of file from blob
                     //let blob: &git2::Blob = obj.as_blob().unwrap();
 
-                    try!(db.upsert_file(&self.id, branch, &repo_entry.path, Some(commit_id)));
+                    let blob_id = format!("{}", entry.id());
+
+                    try!(db.upsert_file(&self.id, branch, &repo_entry.path, Some(commit_id), Some(&blob_id)));
                 },
                 _ => {}
             }
@@ -529,8 +1190,9 @@ impl Repo {
         let git_repo = try!(self.git_repo());
 
         let branch_fullname = try!(self.find_branch(git_repo, branch));
-        
-        let id = try!(git_repo.refname_to_id(&branch_fullname));
+
+        // annotated tags point at a tag object rather than the commit
+        let id = try!(git_repo.revparse_single(&format!("{}^{{commit}}", branch_fullname))).id();
 
         return Ok(id);
     }
@@ -542,14 +1204,51 @@ impl Repo {
         }
     }
     
+    /// set the in-memory state to match what was found on disk. not validated or persisted
     pub fn set_state(&mut self, new_state: SyncState) {
         info!("repo {} {:?} --> {:?}", self.uri, self.sync_state, new_state);
         self.sync_state = new_state;
     }
 
+    /// move to the next sync state, checking it's a valid transition, and save it in the db
+    pub fn transition(&mut self, db: &db::Db, new_state: SyncState) -> RepoResult<()> {
+        if !self.sync_state.can_transition_to(new_state) {
+            return Err(RepoError::InvalidStateTransition(self.sync_state, new_state));
+        }
+
+        self.set_state(new_state);
+
+        let mut db_repo = try!(self.find_or_create_in_db(db));
+        db_repo.sync_state = new_state;
+
+        match new_state {
+            SyncState::Fetched => {
+                db_repo.fetched_datetime = Some(time::get_time());
+            },
+            SyncState::IndexedFiles => {
+                db_repo.indexed_datetime = Some(time::get_time());
+            },
+            _ => {}
+        }
+
+        try!(db.update_repo(&db_repo));
+
+        if new_state.is_in_progress() {
+            self.progress.report(&self.id, new_state, 0, 1);
+        } else {
+            self.progress.report(&self.id, new_state, 1, 1);
+        }
+
+        Ok(())
+    }
+
     pub fn get_repo_path(config: &Config, repo_loc: &RepoLocation) -> RepoResult<PathBuf> {
-        let id = Repo::id(try!(repo_loc.get_remote()));
-        Ok(Path::new(&config.data_dir).join("repos").join(id))
+        Ok(Repo::get_repo_path_for_remote(config, try!(repo_loc.get_remote())))
+    }
+
+    pub fn get_repo_path_for_remote(config: &Config, remote: &str) -> PathBuf {
+        let id = Repo::id(remote);
+        Path::new(&config.data_dir).join("repos").join(id)
     }
 
     pub fn id(remote: &str) -> String {
@@ -559,6 +1258,181 @@ impl Repo {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs::{self,File};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use git2;
+    use db;
+
+    #[test]
+    pub fn test_sync_state_transitions() {
+        assert!(SyncState::NotCloned.can_transition_to(SyncState::Cloning));
+        assert!(SyncState::Cloning.can_transition_to(SyncState::CloneFail));
+        assert!(SyncState::IndexedFiles.can_transition_to(SyncState::Fetching));
+        assert!(SyncState::IndexFilesFail.can_transition_to(SyncState::IndexingFiles));
+        assert!(!SyncState::NotCloned.can_transition_to(SyncState::Fetching));
+        assert!(!SyncState::Fetched.can_transition_to(SyncState::IndexingFiles));
+        assert!(SyncState::IndexedFiles.can_transition_to(SyncState::RemoteRepaired));
+        assert!(SyncState::RemoteRepaired.can_transition_to(SyncState::Fetching));
+    }
+
+    #[test]
+    pub fn test_ref_patterns() {
+        assert_eq!(tag_name("tags/v1.0"), Some("v1.0"));
+        assert_eq!(tag_name("master"), None);
+
+        assert!(is_branch_pattern("all"));
+        assert!(is_branch_pattern("release/*"));
+        assert!(!is_branch_pattern("master"));
+
+        assert!(branch_pattern_matches("all", "feature/x"));
+        assert!(branch_pattern_matches("release/*", "release/1.2"));
+        assert!(!branch_pattern_matches("release/*", "release/1.2/hotfix"));
+    }
+
+    #[test]
+    pub fn test_update_repo_in_db_keeps_interrupted_clone() {
+        let db = db::Db::open_in_memory().unwrap();
+        db.migrate();
+
+        let mut repo = Repo::new(PathBuf::from("codelauf_test_interrupted_clone"), "https://example.com/repo.git".to_string(), vec![], SyncState::NotCloned);
+        repo.update_repo_in_db(&db).unwrap();
+        repo.transition(&db, SyncState::Cloning).unwrap();
 
+        // as probe_fs leaves it when it finds the partial clone's directory
+        repo.set_state(SyncState::Cloned);
+        repo.update_repo_in_db(&db).unwrap();
+
+        assert_eq!(repo.sync_state, SyncState::Cloning);
+        assert_eq!(db.find_repo(&repo.id).unwrap().unwrap().sync_state, SyncState::Cloning);
+    }
 
+    #[test]
+    pub fn test_convert_to_bare() {
+        let path = env::temp_dir().join("codelauf_test_convert_to_bare");
+        let _ = fs::remove_dir_all(&path);
 
+        git2::Repository::init(&path).unwrap();
+        File::create(path.join("README")).unwrap().write_all(b"hello\n").unwrap();
+
+        let mut repo = Repo::new(path.clone(), "https://example.com/repo.git".to_string(), vec![], SyncState::NotCloned);
+        assert!(repo.needs_convert_to_bare());
+
+        repo.probe_fs().unwrap();
+
+        assert!(!repo.needs_convert_to_bare());
+        assert!(git2::Repository::open(&path).unwrap().is_bare());
+        assert!(!path_exists(&path.join("README")));
+        assert!(!path_exists(&repo.old_work_tree_path()));
+        assert_eq!(repo.sync_state, SyncState::Cloned);
+
+        // interrupted after the .git dir was moved into place
+        fs::create_dir(repo.old_work_tree_path()).unwrap();
+        File::create(repo.old_work_tree_path().join("README")).unwrap().write_all(b"hello\n").unwrap();
+        assert!(repo.needs_convert_to_bare());
+
+        repo.probe_fs().unwrap();
+
+        assert!(!path_exists(&repo.old_work_tree_path()));
+        assert!(git2::Repository::open(&path).unwrap().is_bare());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    /// a bare repo with a chain of commits on master, the last one at the tip
+    fn commit_chain(path: &PathBuf, count: usize) -> Vec<git2::Oid> {
+        let _ = fs::remove_dir_all(path);
+
+        let git_repo = git2::Repository::init_bare(path).unwrap();
+        let sig = git2::Signature::now("codelauf", "codelauf@example.com").unwrap();
+        let tree = git_repo.find_tree(git_repo.treebuilder(None).unwrap().write().unwrap()).unwrap();
+
+        let mut oids: Vec<git2::Oid> = vec![];
+
+        for i in 0..count {
+            let parent = oids.last().map(|oid| git_repo.find_commit(*oid).unwrap());
+            let parents: Vec<&git2::Commit> = parent.iter().collect();
+
+            oids.push(git_repo.commit(Some("refs/heads/master"), &sig, &sig, &format!("commit {}", i), &tree, &parents).unwrap());
+        }
+
+        oids
+    }
+
+    fn delete_object(path: &PathBuf, oid: git2::Oid) {
+        let hex = format!("{}", oid);
+
+        fs::remove_file(path.join("objects").join(&hex[..2]).join(&hex[2..])).unwrap();
+    }
+
+    #[test]
+    pub fn test_revwalk_detects_missing_commit() {
+        let db = db::Db::open_in_memory().unwrap();
+        db.migrate();
+
+        let path = env::temp_dir().join("codelauf_test_revwalk_missing");
+        let oids = commit_chain(&path, 3);
+
+        let mut repo = Repo::new(path.clone(), "https://example.com/repo.git".to_string(), vec![], SyncState::Cloned);
+        repo.open_repo().unwrap();
+        repo.revwalk(&db).unwrap();
+
+        delete_object(&path, oids[1]);
+
+        // the tip is still there, but the walk can't get past it
+        repo.open_repo().unwrap();
+        match repo.revwalk(&db) {
+            Err(RepoError::CorruptedRepo(_)) => {},
+            other => panic!("expected a corrupted repo, got {:?}", other),
+        }
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    pub fn test_check_indexed_commits_present() {
+        let path = env::temp_dir().join("codelauf_test_indexed_commits_present");
+        let oids = commit_chain(&path, 2);
+
+        // feature hasn't been fetched into the clone, so its indexed commit isn't expected to be there yet
+        let branches = vec![
+            Branch::new("master".to_string(), Some(format!("{}", oids[0]))),
+            Branch::new("feature".to_string(), Some("0123456789012345678901234567890123456789".to_string())),
+        ];
+
+        let mut repo = Repo::new(path.clone(), "https://example.com/repo.git".to_string(), branches, SyncState::Cloned);
+        repo.open_repo().unwrap();
+        repo.check_indexed_commits_present().unwrap();
+
+        delete_object(&path, oids[0]);
+
+        repo.open_repo().unwrap();
+        match repo.check_indexed_commits_present() {
+            Err(RepoError::CorruptedRepo(_)) => {},
+            other => panic!("expected a corrupted repo, got {:?}", other),
+        }
+
+        // recovering moves the clone aside, so the next sync clones it again
+        repo.move_aside().unwrap();
+        assert!(!repo.dot_git_exists());
+
+        for entry in fs::read_dir(env::temp_dir()).unwrap() {
+            let entry_path = entry.unwrap().path();
+
+            if entry_path.to_string_lossy().contains("codelauf_test_indexed_commits_present.corrupted-") {
+                fs::remove_dir_all(&entry_path).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_sync_state_round_trip() {
+        let state: SyncState = "IndexCommitsFail".parse().unwrap();
+        assert_eq!(state, SyncState::IndexCommitsFail);
+        assert_eq!(state.to_string(), "IndexCommitsFail");
+    }
+}
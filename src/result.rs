@@ -7,6 +7,9 @@ use db;
 use git2;
 use url;
 use rs_es;
+use hyper;
+use zookeeper;
+use repo::SyncState;
 
 pub type RepoResult<T> = Result<T, RepoError>;
 
@@ -23,14 +26,26 @@ pub enum RepoError {
     StringUnicodeError,
     GitError(git2::Error),
     InvalidState(String),
+    InvalidStateTransition(SyncState, SyncState),
     FromUtf8Error,
     UrlParseError(url::ParseError),
     ElasticSearchError(rs_es::error::EsError),
+    ElasticSearchResponseError(String),
+    IndexVersionMismatch(u32, u32),
+    HttpError(hyper::Error),
+    JsonEncodeError(String),
     ParseIntError(num::ParseIntError),
     NoTreeEntryName,
     HeadRefHasNoDirectTarget,
     IoError(io::Error),
     BranchNotFound,
+    NoZooKeeper,
+    ZkError(zookeeper::ZkError),
+    ZkDataError(String),
+    ZkSessionExpired,
+    WorkerLockHeld(String),
+    CredentialsError(String),
+    CorruptedRepo(String),
 }
 
 impl From<SqliteError> for RepoError {
@@ -63,6 +78,12 @@ impl From<rs_es::error::EsError> for RepoError {
     }
 }
 
+impl From<hyper::Error> for RepoError {
+    fn from(err: hyper::Error) -> RepoError {
+        RepoError::HttpError(err)
+    }
+}
+
 impl From<num::ParseIntError> for RepoError {
     fn from(err: num::ParseIntError) -> RepoError {
         RepoError::ParseIntError(err)
@@ -75,4 +96,10 @@ impl From<io::Error> for RepoError {
     }
 }
 
+impl From<zookeeper::ZkError> for RepoError {
+    fn from(err: zookeeper::ZkError) -> RepoError {
+        RepoError::ZkError(err)
+    }
+}
+
     
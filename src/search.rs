@@ -0,0 +1,557 @@
+use std::io::Read;
+use hyper;
+use rustc_serialize::json;
+use rustc_serialize::json::{Json,ToJson};
+use config::SearchOptions;
+use index::{Index,IndexedFile,Commit,CommitFile,Symbol};
+use es_schema::INDEX_ALIAS;
+use es_query::{object,term};
+use glob::{glob_match,glob_to_regexp};
+use repo::Repo;
+use result::*;
+
+/// max number of matching lines printed for each file
+const MAX_SNIPPET_LINES: usize = 5;
+
+/// the shortest ngram in esindex.json. anything shorter can't be found
+const MIN_NGRAM: usize = 3;
+
+/// the ngram match only narrows down a pickaxe search, so candidates are fetched a page at a time
+/// until enough of them really match, giving up after elasticsearch's default result window
+const PICKAXE_PAGE_SIZE: usize = 100;
+const MAX_PICKAXE_CANDIDATES: usize = 10000;
+
+#[derive(Debug,Clone)]
+pub enum SearchHit {
+    File(IndexedFile),
+    Commit(String, Commit),
+    Symbol(Symbol),
+}
+
+fn date_range(field: &str, since: &Option<String>, until: &Option<String>) -> Json {
+    let mut bounds = vec![];
+
+    match *since {
+        Some(ref since) => bounds.push(("gte", since.to_json())),
+        None => {}
+    }
+
+    match *until {
+        Some(ref until) => bounds.push(("lte", until.to_json())),
+        None => {}
+    }
+
+    object(vec![("range", object(vec![(field, object(bounds))]))])
+}
+
+fn regexp(field: &str, value: &str) -> Json {
+    object(vec![("regexp", object(vec![(field, value.to_json())]))])
+}
+
+/// matches files whose path matches the glob, and commits that touched one.
+/// the analysed path fields are split into ngrams, so this uses the whole paths in the raw subfields
+fn path_filter(pattern: &str) -> Json {
+    let path_regexp = glob_to_regexp(pattern);
+
+    object(vec![("bool", object(vec![("should", Json::Array(vec![
+        regexp("path.raw", &path_regexp),
+        regexp("files.path.raw", &path_regexp),
+    ]))]))])
+}
+
+/// remote, path, author and date filters, shared by text and pickaxe searches
+fn common_filters(options: &SearchOptions) -> Vec<Json> {
+    let mut filters = vec![];
+
+    match options.remote {
+        Some(ref remote) => filters.push(term("repo_id", &Repo::id(remote))),
+        None => {}
+    }
+
+    match options.path {
+        Some(ref pattern) => filters.push(path_filter(pattern)),
+        None => {}
+    }
+
+    match options.author {
+        Some(ref author) => {
+            filters.push(object(vec![("bool", object(vec![("should", Json::Array(vec![
+                term("author.name", author),
+                term("author.email", author),
+            ]))]))]));
+        },
+        None => {}
+    }
+
+    if options.since.is_some() || options.until.is_some() {
+        filters.push(object(vec![("bool", object(vec![("should", Json::Array(vec![
+            date_range("commit_date", &options.since, &options.until),
+            date_range("changed_date", &options.since, &options.until),
+        ]))]))]));
+    }
+
+    filters
+}
+
+fn filtered(query: Json, filters: Vec<Json>) -> Json {
+    if filters.is_empty() {
+        query
+    } else {
+        object(vec![("filtered", object(vec![
+            ("query", query),
+            ("filter", object(vec![("bool", object(vec![("must", Json::Array(filters))]))])),
+        ]))])
+    }
+}
+
+/// builds the _search request body.
+/// the text is matched against the ngram analysed fields, requiring all of its ngrams to match,
+/// so it behaves roughly like a substring search.
+/// keywords holds the names a file defines, so it's boosted to rank definitions above mentions.
+/// commits match on the paths they touched and the lines they added.
+/// file documents list the branches they're on, so a branch filter leaves out commits.
+/// commits are kept by the path filter if they touched a matching file
+pub fn build_query(options: &SearchOptions) -> Json {
+    let text_query = object(vec![("multi_match", object(vec![
+        ("query", options.query.to_json()),
+        ("fields", vec!["text", "message", "path", "keywords^3", "files.path", "files.old_path", "files.text"].to_json()),
+        ("operator", "and".to_json()),
+    ]))]);
+
+    let mut filters = common_filters(options);
+
+    match options.branch {
+        Some(ref branch) => filters.push(term("branches", branch)),
+        None => {}
+    }
+
+    object(vec![
+        ("size", options.limit.to_json()),
+        ("query", filtered(text_query, filters)),
+    ])
+}
+
+/// builds the _search request body for a page of commits that may have added or removed the query, newest first.
+/// the ngram match only narrows things down; pickaxe_files then checks each commit properly
+pub fn build_pickaxe_query(options: &SearchOptions, from: usize) -> Json {
+    let diff_query = object(vec![("multi_match", object(vec![
+        ("query", options.query.to_json()),
+        ("fields", vec!["files.text", "files.removed_text"].to_json()),
+        ("operator", "and".to_json()),
+    ]))]);
+
+    object(vec![
+        ("from", from.to_json()),
+        ("size", PICKAXE_PAGE_SIZE.to_json()),
+        ("query", filtered(diff_query, common_filters(options))),
+        ("sort", Json::Array(vec![object(vec![("commit_date", object(vec![("order", "desc".to_json())]))])])),
+    ])
+}
+
+/// builds the _search request body for finding where things called the query are defined
+pub fn build_symbol_query(options: &SearchOptions) -> Json {
+    let name_query = if options.prefix {
+        object(vec![("prefix", object(vec![("name", options.query.to_json())]))])
+    } else {
+        term("name", &options.query)
+    };
+
+    let mut filters = vec![];
+
+    match options.remote {
+        Some(ref remote) => filters.push(term("repo_id", &Repo::id(remote))),
+        None => {}
+    }
+
+    match options.branch {
+        Some(ref branch) => filters.push(term("branch", branch)),
+        None => {}
+    }
+
+    match options.path {
+        Some(ref pattern) => filters.push(regexp("path", &glob_to_regexp(pattern))),
+        None => {}
+    }
+
+    object(vec![
+        ("size", options.limit.to_json()),
+        ("query", filtered(name_query, filters)),
+    ])
+}
+
+/// the files in which a commit changed the number of times the string appears, as git log -S does,
+/// along with the added (+) and removed (-) lines containing it
+pub fn pickaxe_files<'a>(commit: &'a Commit, needle: &str, path: &Option<String>) -> Vec<(&'a CommitFile, Vec<(char, String)>)> {
+    let files = match commit.files {
+        Some(ref files) => files,
+        None => return vec![],
+    };
+
+    let mut result = vec![];
+
+    for file in files.iter() {
+        match *path {
+            Some(ref pattern) if !glob_match(pattern, &file.path.to_string_lossy()) => continue,
+            _ => {}
+        }
+
+        let added = file.text.as_ref().map(|t| &t[..]).unwrap_or("");
+        let removed = file.removed_text.as_ref().map(|t| &t[..]).unwrap_or("");
+
+        if added.matches(needle).count() == removed.matches(needle).count() {
+            continue;
+        }
+
+        let mut lines = vec![];
+
+        for line in added.lines().filter(|l| l.contains(needle)).take(MAX_SNIPPET_LINES) {
+            lines.push(('+', line.to_owned()));
+        }
+
+        for line in removed.lines().filter(|l| l.contains(needle)).take(MAX_SNIPPET_LINES) {
+            lines.push(('-', line.to_owned()));
+        }
+
+        result.push((file, lines));
+    }
+
+    result
+}
+
+/// the lines of a file containing any of the query's words, numbered from 1
+pub fn matching_lines(text: &str, query: &str, max: usize) -> Vec<(usize, String)> {
+    let words: Vec<String> = query.split_whitespace().map(|w| w.to_lowercase()).collect();
+
+    text.lines()
+        .enumerate()
+        .filter(|&(_, line)| {
+            let line = line.to_lowercase();
+            words.iter().any(|w| line.contains(&w[..]))
+        })
+        .take(max)
+        .map(|(i, line)| (i + 1, line.to_owned()))
+        .collect()
+}
+
+/// the hits that could be decoded, and how many hits there were in all
+fn parse_hits(res_str: &str) -> RepoResult<(Vec<SearchHit>, usize)> {
+    let res_json = try!(Json::from_str(res_str).map_err(|e| RepoError::ElasticSearchResponseError(format!("{:?}", e))));
+
+    let hits = try!(res_json.find_path(&["hits", "hits"])
+                    .and_then(|h| h.as_array())
+                    .ok_or(RepoError::ElasticSearchResponseError(res_str.to_owned())));
+
+    let mut result = vec![];
+
+    for hit in hits.iter() {
+        let doc_type = hit.find("_type").and_then(|t| t.as_string()).unwrap_or("");
+        let id = hit.find("_id").and_then(|t| t.as_string()).unwrap_or("");
+        let source = try!(hit.find("_source").ok_or(RepoError::ElasticSearchResponseError(format!("hit without source: {}", hit))));
+
+        // documents written by an older version may not decode, so skip them rather than failing the search
+        let decoded = match doc_type {
+            "file" => json::decode::<IndexedFile>(&source.to_string()).map(|file| SearchHit::File(file)),
+            "commit" => json::decode::<Commit>(&source.to_string()).map(|commit| SearchHit::Commit(id.to_owned(), commit)),
+            "symbol" => json::decode::<Symbol>(&source.to_string()).map(|symbol| SearchHit::Symbol(symbol)),
+            _ => {
+                info!("ignoring search hit of type {}", doc_type);
+                continue;
+            }
+        };
+
+        match decoded {
+            Ok(hit) => result.push(hit),
+            Err(err) => {
+                error!("ignoring {} {} that couldn't be decoded: {:?}", doc_type, id, err);
+            }
+        }
+    }
+
+    Ok((result, hits.len()))
+}
+
+/// rejects searches that can't work, rather than quietly finding nothing
+pub fn check_options(options: &SearchOptions) -> RepoResult<()> {
+    match options.doc_type {
+        Some(ref doc_type) if doc_type != "file" && doc_type != "commit" => {
+            return Err(RepoError::InvalidArgs(format!("unknown search type {}. expected file or commit", doc_type)));
+        },
+        _ => {}
+    }
+
+    if options.pickaxe {
+        if options.branch.is_some() {
+            return Err(RepoError::InvalidArgs("branch can't be used with pickaxe: commits aren't indexed by branch".to_string()));
+        }
+
+        if options.query.chars().count() < MIN_NGRAM {
+            return Err(RepoError::InvalidArgs(format!("pickaxe needs at least {} characters to search for", MIN_NGRAM)));
+        }
+    }
+
+    Ok(())
+}
+
+fn run_search(url: &str, body: &str) -> RepoResult<(Vec<SearchHit>, usize)> {
+    info!("searching {} {}", url, body);
+
+    let client = hyper::Client::new();
+    let mut res = try!(client.post(url).body(body).send());
+
+    let mut res_str = String::new();
+    try!(res.read_to_string(&mut res_str));
+
+    if !res.status.is_success() {
+        return Err(RepoError::ElasticSearchResponseError(format!("{} {}", res.status, res_str)));
+    }
+
+    parse_hits(&res_str)
+}
+
+/// run a text, pickaxe or symbol search
+pub fn search(index: &Index, options: &SearchOptions) -> RepoResult<Vec<SearchHit>> {
+    try!(check_options(options));
+
+    if options.symbol {
+        let url = format!("{}/{}/symbol/_search", index.es_base_url, INDEX_ALIAS);
+
+        return run_search(&url, &build_symbol_query(options).to_string()).map(|(hits, _)| hits);
+    }
+
+    if !options.pickaxe {
+        let url = match options.doc_type {
+            Some(ref doc_type) => format!("{}/{}/{}/_search", index.es_base_url, INDEX_ALIAS, doc_type),
+            None => format!("{}/{}/file,commit/_search", index.es_base_url, INDEX_ALIAS),
+        };
+
+        return run_search(&url, &build_query(options).to_string()).map(|(hits, _)| hits);
+    }
+
+    let url = format!("{}/{}/commit/_search", index.es_base_url, INDEX_ALIAS);
+
+    let mut result = vec![];
+    let mut from = 0;
+
+    while result.len() < options.limit && from < MAX_PICKAXE_CANDIDATES {
+        let (hits, count) = try!(run_search(&url, &build_pickaxe_query(options, from).to_string()));
+
+        for hit in hits {
+            let matched = match hit {
+                SearchHit::Commit(_, ref commit) => !pickaxe_files(commit, &options.query, &options.path).is_empty(),
+                _ => false
+            };
+
+            if matched && result.len() < options.limit {
+                result.push(hit);
+            }
+        }
+
+        if count < PICKAXE_PAGE_SIZE {
+            break;
+        }
+
+        from += PICKAXE_PAGE_SIZE;
+    }
+
+    Ok(result)
+}
+
+/// the representation of a hit returned by the worker's /search endpoint
+pub fn hit_to_json(hit: &SearchHit, options: &SearchOptions) -> Json {
+    let query = &options.query;
+
+    match *hit {
+        SearchHit::File(ref file) => {
+            let lines = match file.text {
+                Some(ref text) => matching_lines(text, query, MAX_SNIPPET_LINES).into_iter().map(|(line_no, line)| {
+                    object(vec![("line", line_no.to_json()), ("text", line.to_json())])
+                }).collect(),
+                None => vec![],
+            };
+
+            object(vec![
+                ("type", "file".to_json()),
+                ("repo_id", file.repo_id.to_json()),
+                ("path", file.path.to_string_lossy().to_json()),
+                ("changed_commit_id", file.changed_commit_id.to_json()),
+                ("changed_date", file.changed_date.to_json()),
+                ("lines", Json::Array(lines)),
+            ])
+        },
+        SearchHit::Commit(ref id, ref commit) => {
+            let subject = commit.message.as_ref().and_then(|m| m.lines().next()).unwrap_or("");
+
+            let mut fields = vec![
+                ("type", "commit".to_json()),
+                ("id", id.to_json()),
+                ("repo_id", commit.repo_id.to_json()),
+                ("author_name", commit.author.name.to_json()),
+                ("author_email", commit.author.email.to_json()),
+                ("commit_date", commit.commit_date.to_json()),
+                ("subject", subject.to_json()),
+            ];
+
+            if options.pickaxe {
+                let files = pickaxe_files(commit, query, &options.path).into_iter().map(|(file, lines)| {
+                    let lines = lines.into_iter().map(|(origin, line)| {
+                        object(vec![("origin", origin.to_string().to_json()), ("text", line.to_json())])
+                    }).collect();
+
+                    object(vec![
+                        ("change_type", file.change_type.to_json()),
+                        ("path", file.path.to_string_lossy().to_json()),
+                        ("lines", Json::Array(lines)),
+                    ])
+                }).collect();
+
+                fields.push(("files", Json::Array(files)));
+            }
+
+            object(fields)
+        },
+        SearchHit::Symbol(ref symbol) => {
+            object(vec![
+                ("type", "symbol".to_json()),
+                ("name", symbol.name.to_json()),
+                ("kind", symbol.kind.to_json()),
+                ("language", symbol.language.to_json()),
+                ("repo_id", symbol.repo_id.to_json()),
+                ("branch", symbol.branch.to_json()),
+                ("path", symbol.path.to_string_lossy().to_json()),
+                ("line", symbol.line.to_json()),
+                ("column", symbol.column.to_json()),
+                ("scope", symbol.scope.to_json()),
+            ])
+        }
+    }
+}
+
+pub fn print_hits(hits: &Vec<SearchHit>, options: &SearchOptions) {
+    for hit in hits.iter() {
+        match *hit {
+            SearchHit::File(ref file) => {
+                println!("file {} {}", &file.repo_id[..8], file.path.display());
+
+                match file.text {
+                    Some(ref text) => {
+                        for (line_no, line) in matching_lines(text, &options.query, MAX_SNIPPET_LINES) {
+                            println!("{:>6}: {}", line_no, line);
+                        }
+                    },
+                    None => {}
+                }
+            },
+            SearchHit::Commit(ref id, ref commit) => {
+                let subject = commit.message.as_ref().and_then(|m| m.lines().next()).unwrap_or("");
+                let author = commit.author.name.as_ref().map(|s| s as &str).unwrap_or("");
+
+                println!("commit {} {} {} {}", &id[..7], &commit.commit_date[..10], author, subject);
+
+                if options.pickaxe {
+                    for (file, lines) in pickaxe_files(commit, &options.query, &options.path) {
+                        println!("    {} {}", file.change_type, file.path.display());
+
+                        for (origin, line) in lines {
+                            println!("        {}{}", origin, line);
+                        }
+                    }
+                }
+            },
+            SearchHit::Symbol(ref symbol) => {
+                let scope = symbol.scope.as_ref().map(|s| format!("{} ", s)).unwrap_or(String::new());
+
+                println!("{} {}{} {}:{}:{} ({} {})", symbol.kind, scope, symbol.name, symbol.path.display(), symbol.line, symbol.column, &symbol.repo_id[..8], symbol.branch);
+            }
+        }
+    }
+
+    println!("{} results", hits.len());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+    use config::SearchOptions;
+    use index::{Commit,CommitFile,Signature};
+
+    #[test]
+    pub fn test_matching_lines() {
+        let text = "fn main() {\n    println!(\"Hello\");\n}\n";
+
+        assert_eq!(matching_lines(text, "hello", 5), vec![(2, "    println!(\"Hello\");".to_owned())]);
+        assert!(matching_lines(text, "goodbye", 5).is_empty());
+    }
+
+    #[test]
+    pub fn test_build_query_filters() {
+        let mut options = SearchOptions::new("fetch".to_owned());
+        assert!(build_query(&options).find_path(&["query", "filtered"]).is_none());
+
+        options.author = Some("someone@example.com".to_owned());
+        assert!(build_query(&options).find_path(&["query", "filtered", "filter"]).is_some());
+
+        options.branch = Some("master".to_owned());
+        options.path = Some("src/*.rs".to_owned());
+        let query = build_query(&options);
+        let filters = query.find_path(&["query", "filtered", "filter", "bool", "must"]).and_then(|f| f.as_array()).unwrap();
+        assert!(filters.iter().any(|f| f.find_path(&["term", "branches"]).is_some()));
+        assert!(filters.iter().any(|f| f.find_path(&["bool", "should"]).and_then(|s| s.as_array()).map(|s| s.iter().any(|p| p.find_path(&["regexp", "path.raw"]).is_some())).unwrap_or(false)));
+    }
+
+    #[test]
+    pub fn test_pickaxe_files() {
+        let mut file = CommitFile::new("modified", PathBuf::from("src/lib.rs"), None);
+        file.add_line('+', b"let x = fetch();\n");
+        file.add_line('-', b"let x = pull();\n");
+
+        let mut moved = CommitFile::new("renamed", PathBuf::from("src/b.rs"), Some(PathBuf::from("src/a.rs")));
+        moved.add_line('+', b"fetch();\n");
+        moved.add_line('-', b"fetch();\n");
+
+        let commit = Commit {
+            parents: vec![],
+            repo_id: "repo".to_owned(),
+            author: Signature { name: None, email: None },
+            committer: Signature { name: None, email: None },
+            commit_date: "2015-10-01T00:00:00+00:00".to_owned(),
+            message: None,
+            files: Some(vec![file, moved]),
+        };
+
+        let files = pickaxe_files(&commit, "fetch", &None);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0.path, PathBuf::from("src/lib.rs"));
+        assert_eq!(files[0].1, vec![('+', "let x = fetch();".to_owned())]);
+
+        assert!(pickaxe_files(&commit, "fetch", &Some("docs/*".to_owned())).is_empty());
+    }
+
+    #[test]
+    pub fn test_check_options() {
+        let mut options = SearchOptions::new("fetch".to_owned());
+        options.pickaxe = true;
+        assert!(check_options(&options).is_ok());
+
+        options.branch = Some("master".to_owned());
+        assert!(check_options(&options).is_err());
+
+        options.branch = None;
+        options.query = "fn".to_owned();
+        assert!(check_options(&options).is_err());
+
+        let mut options = SearchOptions::new("fetch".to_owned());
+        options.doc_type = Some("blob".to_owned());
+        assert!(check_options(&options).is_err());
+    }
+
+    #[test]
+    pub fn test_build_symbol_query() {
+        let mut options = SearchOptions::new("Repo".to_owned());
+        assert!(build_symbol_query(&options).find_path(&["query", "term", "name"]).is_some());
+
+        options.prefix = true;
+        options.branch = Some("master".to_owned());
+        assert!(build_symbol_query(&options).find_path(&["query", "filtered", "query", "prefix", "name"]).is_some());
+    }
+}
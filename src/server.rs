@@ -0,0 +1,269 @@
+use std::sync::Mutex;
+use std::sync::mpsc::Sender;
+use hyper;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use hyper::header::ContentType;
+use hyper::mime::{Mime,TopLevel,SubLevel};
+use hyper::server::{Handler,Request,Response,Listening};
+use hyper::uri::RequestUri;
+use rustc_serialize::json::{Json,ToJson};
+use chrono::UTC;
+use url::form_urlencoded;
+use config::{Config,SearchOptions};
+use db;
+use db::Db;
+use index::Index;
+use commands;
+use search;
+use es_query::object;
+use result::*;
+
+/// something the worker api asks the worker thread to do
+#[derive(Debug,Clone)]
+pub enum WorkerCommand {
+    Sync(String),
+    Recreate(String),
+}
+
+fn error_json(message: &str) -> Json {
+    object(vec![("error", message.to_json())])
+}
+
+fn repository_json(repo: &db::Repository, branches: &Vec<db::RepoBranch>) -> Json {
+    let branches_json = branches.iter().map(|branch| {
+        object(vec![
+            ("name", branch.name.to_json()),
+            ("indexed_commit_id", branch.indexed_commit_id.to_json()),
+        ])
+    }).collect();
+
+    object(vec![
+        ("id", repo.id.to_json()),
+        ("uri", repo.uri.to_json()),
+        ("sync_state", repo.sync_state.to_string().to_json()),
+        ("added_datetime", repo.added_datetime.map(|t| t.sec).to_json()),
+        ("fetched_datetime", repo.fetched_datetime.map(|t| t.sec).to_json()),
+        ("indexed_datetime", repo.indexed_datetime.map(|t| t.sec).to_json()),
+        ("branches", Json::Array(branches_json)),
+    ])
+}
+
+pub fn search_options_from_query(query: &str) -> RepoResult<SearchOptions> {
+    let pairs = form_urlencoded::parse(query.as_bytes());
+
+    let mut options = match pairs.iter().find(|&&(ref k, _)| k == "q") {
+        Some(&(_, ref q)) => SearchOptions::new(q.clone()),
+        None => return Err(RepoError::InvalidArgs("missing q parameter".to_owned())),
+    };
+
+    for &(ref key, ref value) in pairs.iter() {
+        let value = Some(value.clone());
+
+        match &key[..] {
+            "remote" => options.remote = value,
+            "branch" => options.branch = value,
+            "path" => options.path = value,
+            "author" => options.author = value,
+            "since" => options.since = value,
+            "until" => options.until = value,
+            "type" => options.doc_type = value,
+            "symbol" => options.symbol = value == Some("true".to_owned()),
+            "prefix" => options.prefix = value == Some("true".to_owned()),
+            "pickaxe" => options.pickaxe = value == Some("true".to_owned()),
+            "limit" => options.limit = try!(value.and_then(|v| v.parse::<usize>().ok()).ok_or(RepoError::InvalidArgs("invalid limit".to_owned()))),
+            _ => {}
+        }
+    }
+
+    Ok(options)
+}
+
+/// serves the worker management api described in the readme, plus /search.
+/// hyper calls this from its own threads, so each request opens its own db connection,
+/// and sync requests are passed to the worker thread over a channel
+pub struct ApiHandler {
+    config: Config,
+    start_time: String,
+    commands: Mutex<Sender<WorkerCommand>>,
+}
+
+impl ApiHandler {
+    pub fn new(config: &Config, commands: Sender<WorkerCommand>) -> ApiHandler {
+        ApiHandler {
+            config: config.clone(),
+            start_time: UTC::now().to_rfc3339(),
+            commands: Mutex::new(commands),
+        }
+    }
+
+    fn route(&self, method: &Method, uri: &str) -> RepoResult<(StatusCode, Json)> {
+        let mut uri_parts = uri.splitn(2, '?');
+        let path = uri_parts.next().unwrap_or("");
+        let query = uri_parts.next().unwrap_or("");
+
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+        if segments.len() > 3 {
+            return Ok((StatusCode::NotFound, error_json("not found")));
+        }
+
+        let segment = |i: usize| segments.get(i).map(|s| *s);
+
+        match (method, segment(0), segment(1), segment(2)) {
+            (&Method::Get, Some("repositories"), None, None) => self.list_repositories(),
+            (&Method::Get, Some("repositories"), Some(id), None) => self.get_repository(id),
+            (&Method::Post, Some("repositories"), Some(id), Some("sync")) => self.send_command(id, WorkerCommand::Sync(id.to_owned())),
+            (&Method::Post, Some("repositories"), Some(id), Some("recreate")) => self.send_command(id, WorkerCommand::Recreate(id.to_owned())),
+            (&Method::Get, Some("status"), None, None) => self.status(),
+            (&Method::Get, Some("search"), None, None) => self.search(query),
+            (_, Some("repositories"), _, _) | (_, Some("status"), None, None) | (_, Some("search"), None, None) => {
+                Ok((StatusCode::MethodNotAllowed, error_json("method not allowed")))
+            },
+            _ => Ok((StatusCode::NotFound, error_json("not found")))
+        }
+    }
+
+    /// a plain connection, without migrating. the worker did that before starting the api
+    fn open_db(&self) -> RepoResult<Db> {
+        Db::open(&commands::db_path(&self.config)).map_err(|e| RepoError::SqlError(e))
+    }
+
+    fn repositories_json(&self, db: &Db) -> RepoResult<Json> {
+        let mut repos = vec![];
+
+        for repo in try!(db.find_repos()) {
+            let branches = try!(db.find_branches(&repo.id));
+
+            repos.push(repository_json(&repo, &branches));
+        }
+
+        Ok(Json::Array(repos))
+    }
+
+    fn list_repositories(&self) -> RepoResult<(StatusCode, Json)> {
+        let db = try!(self.open_db());
+
+        Ok((StatusCode::Ok, try!(self.repositories_json(&db))))
+    }
+
+    fn get_repository(&self, id: &str) -> RepoResult<(StatusCode, Json)> {
+        let db = try!(self.open_db());
+
+        match try!(db.find_repo(id)) {
+            Some(repo) => {
+                let branches = try!(db.find_branches(&repo.id));
+
+                Ok((StatusCode::Ok, repository_json(&repo, &branches)))
+            },
+            None => Ok((StatusCode::NotFound, error_json("no such repository")))
+        }
+    }
+
+    /// the worker picks the command up between syncs
+    fn send_command(&self, id: &str, command: WorkerCommand) -> RepoResult<(StatusCode, Json)> {
+        let db = try!(self.open_db());
+
+        if try!(db.find_repo(id)).is_none() {
+            return Ok((StatusCode::NotFound, error_json("no such repository")));
+        }
+
+        info!("api requested {:?}", command);
+
+        let sent = self.commands.lock().unwrap().send(command);
+
+        match sent {
+            Ok(()) => Ok((StatusCode::Accepted, object(vec![("queued", true.to_json())]))),
+            Err(_) => Ok((StatusCode::ServiceUnavailable, error_json("worker is not running")))
+        }
+    }
+
+    fn status(&self) -> RepoResult<(StatusCode, Json)> {
+        let db = try!(self.open_db());
+
+        Ok((StatusCode::Ok, object(vec![
+            ("start_time", self.start_time.to_json()),
+            ("zookeeper", self.config.zookeeper.is_some().to_json()),
+            ("interval_secs", self.config.sync_config.interval_secs.to_json()),
+            ("repositories", try!(self.repositories_json(&db))),
+        ])))
+    }
+
+    fn search(&self, query: &str) -> RepoResult<(StatusCode, Json)> {
+        let options = match search_options_from_query(query) {
+            Ok(options) => options,
+            Err(RepoError::InvalidArgs(message)) => return Ok((StatusCode::BadRequest, error_json(&message))),
+            Err(err) => return Err(err),
+        };
+
+        match search::check_options(&options) {
+            Err(RepoError::InvalidArgs(message)) => return Ok((StatusCode::BadRequest, error_json(&message))),
+            result => try!(result),
+        }
+
+        let index = try!(Index::new_for_config(&self.config));
+
+        let hits = try!(search::search(&index, &options));
+
+        Ok((StatusCode::Ok, Json::Array(hits.iter().map(|hit| search::hit_to_json(hit, &options)).collect())))
+    }
+}
+
+impl Handler for ApiHandler {
+    fn handle<'a,'k>(&'a self, req: Request<'a,'k>, mut res: Response<'a>) {
+        let uri = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.clone(),
+            _ => String::new(),
+        };
+
+        info!("api {} {}", req.method, uri);
+
+        let (status, body) = match self.route(&req.method, &uri) {
+            Ok(result) => result,
+            Err(err) => {
+                error!("error handling api request {}: {:?}", uri, err);
+                (StatusCode::InternalServerError, error_json(&format!("{:?}", err)))
+            }
+        };
+
+        *res.status_mut() = status;
+        res.headers_mut().set(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])));
+
+        match res.send(body.to_string().as_bytes()) {
+            Err(err) => {
+                error!("error sending api response: {:?}", err);
+            },
+            _ => {}
+        }
+    }
+}
+
+/// start serving the api in the background. it stops when the returned listener is closed
+pub fn start(config: &Config, addr: &str, commands: Sender<WorkerCommand>) -> RepoResult<Listening> {
+    info!("worker api listening on {}", addr);
+
+    let server = try!(hyper::Server::http(addr));
+
+    Ok(try!(server.handle(ApiHandler::new(config, commands))))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_search_options_from_query() {
+        let options = search_options_from_query("q=fetch%20repo&type=commit&limit=5").unwrap();
+
+        assert_eq!(options.query, "fetch repo");
+        assert_eq!(options.doc_type, Some("commit".to_owned()));
+        assert_eq!(options.limit, 5);
+
+        assert!(search_options_from_query("type=file").is_err());
+
+        match search_options_from_query("q=fetch&limit=lots") {
+            Err(RepoError::InvalidArgs(message)) => assert_eq!(message, "invalid limit"),
+            other => panic!("expected invalid args, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+use std::sync::mpsc::{channel,Receiver};
+use hyper::server::Listening;
+use time;
+
+use config::Config;
+use db;
+use db::Db;
+use result::*;
+use repo::*;
+use zk::{Zk,ZkRepository,ZkRepoProgress};
+use commands;
+use es_schema::EsSchema;
+use server;
+use server::WorkerCommand;
+
+/// the long-running sync process.
+/// every interval it loops over the repositories table and runs
+/// fetch -> revwalk -> index for each one.
+/// a failure syncing one repo is logged and doesn't stop the others.
+///
+/// if zookeeper is configured then the /repositories nodes are the source of truth,
+/// and the repositories and branches tables are kept in step with them.
+/// only one worker may be active at a time, which is enforced by a lock in zookeeper.
+/// zookeeper also holds a backup of each branch's indexed commit id.
+///
+/// if http_listen is set, the worker management api is served on it,
+/// and sync requests made through it are run between scheduled syncs.
+pub struct Worker {
+    pub config: Config,
+    pub db: Db,
+    pub zk: Option<Zk>,
+    pub zk_repos: Vec<ZkRepository>,
+    pub commands: Receiver<WorkerCommand>,
+    pub http: Option<Listening>,
+}
+
+impl Worker {
+    pub fn new(config: &Config) -> RepoResult<Worker> {
+        let db = try!(commands::open_db(config));
+
+        try!(try!(EsSchema::new_for_config(config)).ensure_index());
+
+        let zk = match config.zookeeper {
+            Some(_) => {
+                let mut zk = try!(Zk::new_for_config(config));
+
+                try!(zk.acquire_worker_lock(config.sync_config.wait_for_lock));
+
+                try!(zk.register_worker());
+
+                Some(zk)
+            },
+            None => {
+                info!("zookeeper not configured. syncing repositories already in the db");
+                None
+            }
+        };
+
+        let (commands_tx, commands_rx) = channel();
+
+        let http = match config.sync_config.http_listen {
+            Some(ref addr) => Some(try!(server::start(config, addr, commands_tx))),
+            None => None,
+        };
+
+        Ok(Worker {
+            config: config.clone(),
+            db: db,
+            zk: zk,
+            zk_repos: vec![],
+            commands: commands_rx,
+            http: http,
+        })
+    }
+
+    pub fn run(&mut self) -> RepoResult<()> {
+        info!("starting sync worker");
+
+        try!(self.sync_registry());
+
+        loop {
+            try!(self.sync_all());
+
+            try!(self.wait_for_next_run());
+        }
+    }
+
+    /// sleep until the sync interval has passed,
+    /// or until zookeeper tells us the repository list has changed.
+    /// requests from the api are handled while waiting
+    pub fn wait_for_next_run(&mut self) -> RepoResult<()> {
+        let interval = self.config.sync_config.interval_secs;
+        info!("sync run complete. sleeping for {} seconds", interval);
+
+        let deadline = time::get_time().sec + interval as i64;
+
+        while time::get_time().sec < deadline {
+            let changed = match self.zk {
+                Some(ref zk) => try!(zk.repositories_changed()),
+                None => false,
+            };
+
+            if changed {
+                info!("repositories changed in zookeeper");
+
+                match self.sync_registry() {
+                    Ok(()) => return Ok(()),
+                    Err(err) => {
+                        error!("error reading repositories from zookeeper: {:?}", err);
+                    }
+                }
+            }
+
+            while let Ok(command) = self.commands.try_recv() {
+                match self.run_command(&command) {
+                    Err(err) => {
+                        error!("error running {:?}: {:?}", command, err);
+                    },
+                    _ => {}
+                }
+            }
+
+            thread::sleep(Duration::from_secs(1));
+        }
+
+        Ok(())
+    }
+
+    pub fn run_command(&self, command: &WorkerCommand) -> RepoResult<()> {
+        info!("running {:?}", command);
+
+        match *command {
+            WorkerCommand::Sync(ref repo_id) => {
+                let db_repo = try!(self.find_repo(repo_id));
+
+                self.sync_repo(&db_repo)
+            },
+            WorkerCommand::Recreate(ref repo_id) => {
+                let db_repo = try!(self.find_repo(repo_id));
+
+                try!(self.remove_clone(&db_repo));
+
+                let db_repo = try!(self.find_repo(repo_id));
+
+                self.sync_repo(&db_repo)
+            }
+        }
+    }
+
+    fn find_repo(&self, repo_id: &str) -> RepoResult<db::Repository> {
+        try!(self.db.find_repo(repo_id)).ok_or(RepoError::InvalidArgs(format!("no repository {}", repo_id)))
+    }
+
+    /// delete the clone so the next sync starts from a fresh one, keeping what's been indexed
+    fn remove_clone(&self, db_repo: &db::Repository) -> RepoResult<()> {
+        let db_branches = try!(self.db.find_branches(&db_repo.id));
+
+        let mut repo = Repo::new_for_db_repo(db_repo, &db_branches);
+
+        commands::remove_clone(&self.config, &self.db, &mut repo, false)
+    }
+
+    /// re-read the repository nodes from zookeeper, renewing the watches,
+    /// and add or remove rows in the db to match
+    pub fn sync_registry(&mut self) -> RepoResult<()> {
+        let zk_repos = match self.zk {
+            Some(ref zk) => try!(zk.watch_repositories()),
+            None => return Ok(()),
+        };
+
+        let db_repos = try!(self.db.find_repos());
+
+        for db_repo in db_repos.iter() {
+            if !zk_repos.iter().any(|zk_repo| zk_repo.url == db_repo.uri) {
+                info!("repository {} removed from zookeeper. no longer syncing", db_repo.uri);
+
+                try!(self.db.delete_repo(&db_repo.id));
+
+                match self.zk {
+                    Some(ref zk) => {
+                        for old_zk_repo in self.zk_repos.iter().filter(|zk_repo| zk_repo.url == db_repo.uri) {
+                            try!(zk.progress.remove(&old_zk_repo.id));
+
+                            try!(zk.remove_indexed(&old_zk_repo.id));
+                        }
+                    },
+                    None => {}
+                }
+            }
+        }
+
+        for zk_repo in zk_repos.iter() {
+            try!(self.add_or_update_repo(zk_repo));
+        }
+
+        self.zk_repos = zk_repos;
+
+        Ok(())
+    }
+
+    fn add_or_update_repo(&self, zk_repo: &ZkRepository) -> RepoResult<()> {
+        let branches = zk_repo.branches.iter().map(|b| Branch::new(b.name.clone(), None)).collect();
+
+        let path = Repo::get_repo_path_for_remote(&self.config, &zk_repo.url);
+
+        let mut repo = Repo::new(path, zk_repo.url.clone(), branches, SyncState::NotCloned);
+        repo.branch_patterns = zk_repo.branch_patterns.clone();
+        repo.tag_patterns = zk_repo.tags.clone();
+
+        let mut db_repo = try!(repo.find_or_create_in_db(&self.db));
+
+        if db_repo.branch_patterns != zk_repo.branch_patterns || db_repo.tag_patterns != zk_repo.tags {
+            info!("patterns of {} changed to branches {:?} tags {:?}", zk_repo.url, zk_repo.branch_patterns, zk_repo.tags);
+
+            db_repo.branch_patterns = zk_repo.branch_patterns.clone();
+            db_repo.tag_patterns = zk_repo.tags.clone();
+
+            try!(self.db.update_repo(&db_repo));
+        }
+
+        let db_branches = try!(self.db.find_branches(&db_repo.id));
+
+        // tags and branches matching a pattern are added and retired by the sync itself
+        let discovered = |name: &str| {
+            tag_name(name).is_some() || zk_repo.branch_patterns.iter().any(|pattern| branch_pattern_matches(pattern, name))
+        };
+
+        // a new branch row on a fresh worker is seeded with the backed up indexed commit id,
+        // which is checked against the repo once it has been cloned
+        for zk_branch in zk_repo.branches.iter() {
+            if !db_branches.iter().any(|b| b.name == zk_branch.name) {
+                let indexed_commit_id = zk_repo.indexed.get(&zk_branch.name).cloned().or(zk_branch.indexed_commit_id.clone());
+
+                info!("tracking new branch {} of {} indexed at {:?}", zk_branch.name, zk_repo.url, indexed_commit_id);
+
+                try!(self.db.insert_branch(&db::RepoBranch::new(db_repo.id.clone(), zk_branch.name.clone(), indexed_commit_id)));
+            }
+        }
+
+        // so are discovered refs that were backed up, ready for the sync to keep or retire them
+        for (name, commit_id) in zk_repo.indexed.iter().filter(|&(name, _)| discovered(name)) {
+            if !db_branches.iter().any(|b| b.name == *name) {
+                info!("restoring discovered ref {} of {} indexed at {}", name, zk_repo.url, commit_id);
+
+                try!(self.db.insert_branch(&db::RepoBranch::new(db_repo.id.clone(), name.clone(), Some(commit_id.clone()))));
+            }
+        }
+
+        for db_branch in db_branches.iter().filter(|b| !discovered(&b.name)) {
+            if !zk_repo.branches.iter().any(|b| b.name == db_branch.name) {
+                info!("no longer tracking branch {} of {}", db_branch.name, zk_repo.url);
+
+                try!(self.db.delete_branch(&db_repo.id, &db_branch.name));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn sync_all(&mut self) -> RepoResult<()> {
+        let db_repos = try!(self.db.find_repos());
+
+        info!("syncing {} repositories", db_repos.len());
+
+        for db_repo in db_repos {
+            match self.sync_repo(&db_repo) {
+                Err(err) => {
+                    error!("error syncing repo {}: {:?}", db_repo.uri, err);
+                },
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn sync_repo(&self, db_repo: &db::Repository) -> RepoResult<()> {
+        info!("syncing repo {}", db_repo.uri);
+
+        let db_branches = try!(self.db.find_branches(&db_repo.id));
+
+        let mut repo = Repo::new_for_db_repo(db_repo, &db_branches);
+        repo.use_credentials(&self.config);
+
+        match self.zk {
+            Some(ref zk) => {
+                // published under the repository node's id, so readers can match it up
+                match self.zk_repos.iter().find(|zk_repo| zk_repo.url == db_repo.uri) {
+                    Some(zk_repo) => {
+                        repo.progress = Rc::new(ZkRepoProgress::new(zk.progress.clone(), &zk_repo.id));
+                    },
+                    None => {}
+                }
+            },
+            None => {}
+        }
+
+        try!(repo.probe_fs());
+        try!(repo.update_repo_in_db(&self.db));
+
+        try!(commands::ensure_indexed(&self.config, &self.db, &mut repo));
+
+        self.backup_branches(db_repo)
+    }
+
+    fn backup_branches(&self, db_repo: &db::Repository) -> RepoResult<()> {
+        let zk = match self.zk {
+            Some(ref zk) => zk,
+            None => return Ok(()),
+        };
+
+        match self.zk_repos.iter().find(|zk_repo| zk_repo.url == db_repo.uri) {
+            Some(zk_repo) => {
+                let db_branches = try!(self.db.find_branches(&db_repo.id));
+
+                zk.backup_branches(&zk_repo.id, &db_branches)
+            },
+            None => Ok(())
+        }
+    }
+}
@@ -0,0 +1,543 @@
+use std::str;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::{BTreeMap,HashMap};
+use std::time::Duration;
+use std::sync::mpsc::{channel,Sender,Receiver};
+use rustc_serialize::json::{Json,ToJson};
+use chrono::UTC;
+use zookeeper::{ZooKeeper,ZkError,Watcher,WatchedEvent,WatchedEventType,KeeperState,CreateMode};
+use zookeeper::acls;
+use config::Config;
+use db;
+use result::*;
+use progress::*;
+use repo::{SyncState,is_branch_pattern};
+
+/// everything lives under /codelauf, so the connect string doesn't need a chroot
+pub const REPOSITORIES_PATH: &'static str = "/codelauf/repositories";
+pub const INDEXED_PATH: &'static str = "/codelauf/indexed";
+pub const WORKERS_PATH: &'static str = "/codelauf/workers";
+pub const WORKER_LOCK_PATH: &'static str = "/codelauf/workers/lock";
+pub const WORKER_PATH: &'static str = "/codelauf/workers/0";
+pub const WORKER_REPOSITORIES_PATH: &'static str = "/codelauf/workers/0/repositories";
+
+const SESSION_TIMEOUT_SECS: u64 = 10;
+
+/// what the worker needs to know about when a watch fires
+#[derive(Debug,Clone)]
+pub enum ZkEvent {
+    RepositoriesChanged,
+    LockNodeChanged,
+    SessionExpired,
+}
+
+/// forwards watch notifications to the worker thread.
+/// node events are reported as the given event,
+/// and session expiry is always reported so the worker knows it has lost its lock
+pub struct ChannelWatcher {
+    tx: Sender<ZkEvent>,
+    node_event: ZkEvent,
+}
+
+impl ChannelWatcher {
+    pub fn new(tx: Sender<ZkEvent>, node_event: ZkEvent) -> ChannelWatcher {
+        ChannelWatcher {
+            tx: tx,
+            node_event: node_event,
+        }
+    }
+}
+
+impl Watcher for ChannelWatcher {
+    fn handle(&self, event: &WatchedEvent) {
+        info!("zookeeper event {:?}", event);
+
+        match event.keeper_state {
+            KeeperState::Expired => {
+                let _ = self.tx.send(ZkEvent::SessionExpired);
+                return;
+            },
+            _ => {}
+        }
+
+        match event.event_type {
+            WatchedEventType::NodeCreated |
+            WatchedEventType::NodeDeleted |
+            WatchedEventType::NodeDataChanged |
+            WatchedEventType::NodeChildrenChanged => {
+                let _ = self.tx.send(self.node_event.clone());
+            },
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct ZkBranch {
+    pub name: String,
+    pub indexed_commit_id: Option<String>,
+}
+
+impl ZkBranch {
+    pub fn new(name: String, indexed_commit_id: Option<String>) -> ZkBranch {
+        ZkBranch {
+            name: name,
+            indexed_commit_id: indexed_commit_id,
+        }
+    }
+
+    /// branches may be listed either as a bare name or as an object with a name
+    pub fn new_from_json(json: &Json) -> Option<ZkBranch> {
+        match *json {
+            Json::String(ref name) => Some(ZkBranch::new(name.clone(), None)),
+            Json::Object(ref obj) => {
+                obj.get("name").and_then(|n| n.as_string()).map(|name| {
+                    let indexed_commit_id = obj.get("indexed_commit_id")
+                        .and_then(|c| c.as_string())
+                        .map(|c| c.to_owned());
+
+                    ZkBranch::new(name.to_owned(), indexed_commit_id)
+                })
+            },
+            _ => None
+        }
+    }
+}
+
+/// contents of a /repositories/{id} node
+#[derive(Debug,Clone)]
+pub struct ZkRepository {
+    pub id: String,
+    pub repo_type: String,
+    pub url: String,
+    pub branches: Vec<ZkBranch>,
+    pub branch_patterns: Vec<String>, // globs of remote branches to track, or all
+    pub tags: Vec<String>, // globs of tags to index as well
+    pub indexed: BTreeMap<String, String>, // backed up indexed commit id of each tracked branch and tag
+}
+
+impl ZkRepository {
+    pub fn new_from_node_data(id: &str, data: &[u8]) -> RepoResult<ZkRepository> {
+        let data_str = try!(str::from_utf8(data).map_err(|_| RepoError::StringUnicodeError));
+
+        let json = try!(Json::from_str(data_str).map_err(|e| RepoError::ZkDataError(format!("repository {}: {:?}", id, e))));
+
+        let obj = try!(json.as_object().ok_or(RepoError::ZkDataError(format!("repository {}: not an object", id))));
+
+        let repo_type = obj.get("type")
+            .and_then(|t| t.as_string())
+            .unwrap_or("git")
+            .to_owned();
+
+        let url = try!(obj.get("url")
+                       .and_then(|u| u.as_string())
+                       .ok_or(RepoError::ZkDataError(format!("repository {}: no url", id))))
+            .to_owned();
+
+        let all_branches: Vec<ZkBranch> = match obj.get("branches").and_then(|b| b.as_array()) {
+            Some(branches) => branches.iter().filter_map(|b| ZkBranch::new_from_json(b)).collect(),
+            None => vec![ZkBranch::new("master".to_string(), None)],
+        };
+
+        let (branch_patterns, branches): (Vec<ZkBranch>, Vec<ZkBranch>) = all_branches.into_iter().partition(|b| is_branch_pattern(&b.name));
+
+        let tags = match obj.get("tags").and_then(|t| t.as_array()) {
+            Some(tags) => tags.iter().filter_map(|t| t.as_string()).map(|t| t.to_owned()).collect(),
+            None => vec![],
+        };
+
+        Ok(ZkRepository {
+            id: id.to_owned(),
+            repo_type: repo_type,
+            url: url,
+            branches: branches,
+            branch_patterns: branch_patterns.into_iter().map(|b| b.name).collect(),
+            tags: tags,
+            indexed: BTreeMap::new(),
+        })
+    }
+}
+
+/// contents of an /indexed/{id} node: the indexed commit id of each branch and tag
+pub fn indexed_from_node_data(id: &str, data: &[u8]) -> RepoResult<BTreeMap<String, String>> {
+    let data_str = try!(str::from_utf8(data).map_err(|_| RepoError::StringUnicodeError));
+
+    let json = try!(Json::from_str(data_str).map_err(|e| RepoError::ZkDataError(format!("indexed {}: {:?}", id, e))));
+
+    let obj = try!(json.as_object().ok_or(RepoError::ZkDataError(format!("indexed {}: not an object", id))));
+
+    Ok(obj.iter()
+       .filter_map(|(name, commit_id)| commit_id.as_string().map(|c| (name.clone(), c.to_owned())))
+       .collect())
+}
+
+/// publishes status and percentage complete of each repo to
+/// an ephemeral node at /workers/0/repositories/{id},
+/// where id is the repository's node id under /repositories
+pub struct ZkProgress {
+    zk: Rc<ZooKeeper>,
+    last_published: RefCell<HashMap<String, (SyncState, u32)>>,
+}
+
+impl ZkProgress {
+    pub fn new(zk: Rc<ZooKeeper>) -> ZkProgress {
+        ZkProgress {
+            zk: zk,
+            last_published: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn publish(&self, zk_id: &str, status: SyncState, progress: u32) -> RepoResult<()> {
+        let path = format!("{}/{}", WORKER_REPOSITORIES_PATH, zk_id);
+
+        let mut obj = BTreeMap::new();
+        obj.insert("status".to_string(), status.to_string().to_json());
+        obj.insert("progress".to_string(), progress.to_json());
+        let data = Json::Object(obj).to_string().into_bytes();
+
+        match self.zk.set_data(&path, data.clone(), -1) {
+            Ok(_) => {},
+            Err(ZkError::NoNode) => {
+                try!(self.zk.create(&path, data, acls::OPEN_ACL_UNSAFE.clone(), CreateMode::Ephemeral));
+            },
+            Err(e) => return Err(RepoError::ZkError(e)),
+        }
+
+        Ok(())
+    }
+
+    pub fn remove(&self, zk_id: &str) -> RepoResult<()> {
+        let path = format!("{}/{}", WORKER_REPOSITORIES_PATH, zk_id);
+
+        self.last_published.borrow_mut().remove(zk_id);
+
+        match self.zk.delete(&path, -1) {
+            Ok(()) | Err(ZkError::NoNode) => Ok(()),
+            Err(e) => Err(RepoError::ZkError(e)),
+        }
+    }
+}
+
+/// the progress of one repo, published under its repository node id
+/// rather than the db id the sync reports it with
+pub struct ZkRepoProgress {
+    progress: Rc<ZkProgress>,
+    zk_id: String,
+}
+
+impl ZkRepoProgress {
+    pub fn new(progress: Rc<ZkProgress>, zk_id: &str) -> ZkRepoProgress {
+        ZkRepoProgress {
+            progress: progress,
+            zk_id: zk_id.to_owned(),
+        }
+    }
+}
+
+impl Progress for ZkRepoProgress {
+    /// only writes to zookeeper when the status or whole percentage changes,
+    /// because the git transfer progress callback fires very often
+    fn report(&self, _repo_id: &str, status: SyncState, done: usize, total: usize) {
+        let progress = percentage(done, total);
+
+        let current = (status, progress);
+
+        if self.progress.last_published.borrow().get(&self.zk_id) == Some(&current) {
+            return;
+        }
+
+        match self.progress.publish(&self.zk_id, status, progress) {
+            Ok(()) => {
+                self.progress.last_published.borrow_mut().insert(self.zk_id.clone(), current);
+            },
+            Err(err) => {
+                info!("error publishing progress of {}: {:?}", self.zk_id, err);
+            }
+        }
+    }
+}
+
+pub struct Zk {
+    pub zk: Rc<ZooKeeper>,
+    pub progress: Rc<ZkProgress>,
+    pub lock_node: Option<String>,
+    events_tx: Sender<ZkEvent>,
+    events_rx: Receiver<ZkEvent>,
+}
+
+impl Zk {
+    pub fn new_for_config(config: &Config) -> RepoResult<Zk> {
+        let zk_str: &str = try!(config.zookeeper.as_ref().ok_or(RepoError::NoZooKeeper));
+
+        info!("connecting to zookeeper at {}", zk_str);
+
+        let (tx, rx) = channel();
+
+        let zk = try!(ZooKeeper::connect(zk_str, Duration::from_secs(SESSION_TIMEOUT_SECS), ChannelWatcher::new(tx.clone(), ZkEvent::RepositoriesChanged)));
+
+        let zk = Rc::new(zk);
+
+        let zk = Zk {
+            zk: zk.clone(),
+            progress: Rc::new(ZkProgress::new(zk)),
+            lock_node: None,
+            events_tx: tx,
+            events_rx: rx,
+        };
+
+        try!(zk.ensure_path(REPOSITORIES_PATH));
+        try!(zk.ensure_path(INDEXED_PATH));
+        try!(zk.ensure_path(WORKERS_PATH));
+
+        Ok(zk)
+    }
+
+    /// create a persistent node and any missing parents, ignoring ones that already exist
+    pub fn ensure_path(&self, path: &str) -> RepoResult<()> {
+        let mut current = String::new();
+
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            current.push('/');
+            current.push_str(part);
+
+            match self.zk.create(&current, vec![], acls::OPEN_ACL_UNSAFE.clone(), CreateMode::Persistent) {
+                Ok(_) | Err(ZkError::NodeExists) => {},
+                Err(e) => return Err(RepoError::ZkError(e)),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn watcher(&self) -> ChannelWatcher {
+        ChannelWatcher::new(self.events_tx.clone(), ZkEvent::RepositoriesChanged)
+    }
+
+    /// standard zookeeper lock recipe:
+    /// create an ephemeral sequential node under /workers/lock.
+    /// the worker with the lowest sequence number holds the lock.
+    /// everyone else watches the node just before theirs, so when the holder exits
+    /// or its session expires the next in line takes over.
+    /// if wait is false, give up straight away if someone else holds the lock.
+    pub fn acquire_worker_lock(&mut self, wait: bool) -> RepoResult<()> {
+        try!(self.ensure_path(WORKER_LOCK_PATH));
+
+        let lock_prefix = format!("{}/worker-", WORKER_LOCK_PATH);
+        let lock_node = try!(self.zk.create(&lock_prefix, vec![], acls::OPEN_ACL_UNSAFE.clone(), CreateMode::EphemeralSequential));
+        let lock_name = lock_node[WORKER_LOCK_PATH.len() + 1..].to_owned();
+
+        info!("created worker lock node {}", lock_node);
+
+        loop {
+            let mut children = try!(self.zk.get_children(WORKER_LOCK_PATH, false));
+            children.sort();
+
+            let position = try!(children.iter().position(|c| *c == lock_name)
+                                .ok_or(RepoError::ZkSessionExpired));
+
+            if position == 0 {
+                info!("acquired worker lock");
+                self.lock_node = Some(lock_node);
+                return Ok(());
+            }
+
+            let holder = children[0].clone();
+
+            if !wait {
+                try!(self.zk.delete(&lock_node, -1));
+                return Err(RepoError::WorkerLockHeld(holder));
+            }
+
+            let predecessor = format!("{}/{}", WORKER_LOCK_PATH, children[position - 1]);
+
+            info!("worker lock held by {}. waiting for {} to go away", holder, predecessor);
+
+            let (tx, rx) = channel();
+
+            match try!(self.zk.exists_w(&predecessor, ChannelWatcher::new(tx, ZkEvent::LockNodeChanged))) {
+                Some(_) => {
+                    match rx.recv() {
+                        Ok(ZkEvent::SessionExpired) | Err(_) => return Err(RepoError::ZkSessionExpired),
+                        _ => {}
+                    }
+                },
+                None => {}
+            }
+        }
+    }
+
+    /// record when this worker started in /workers/0,
+    /// which is the parent of the per-repo progress nodes
+    pub fn register_worker(&self) -> RepoResult<()> {
+        try!(self.ensure_path(WORKER_REPOSITORIES_PATH));
+
+        let mut obj = BTreeMap::new();
+        obj.insert("start_time".to_string(), UTC::now().to_rfc3339().to_json());
+        let data = Json::Object(obj).to_string().into_bytes();
+
+        try!(self.zk.set_data(WORKER_PATH, data, -1));
+
+        Ok(())
+    }
+
+    pub fn release_worker_lock(&mut self) -> RepoResult<()> {
+        match self.lock_node.take() {
+            Some(lock_node) => {
+                info!("releasing worker lock {}", lock_node);
+                try!(self.zk.delete(&lock_node, -1));
+            },
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    /// read every repository node, leaving a watch on the list and on each node
+    pub fn watch_repositories(&self) -> RepoResult<Vec<ZkRepository>> {
+        let children = try!(self.zk.get_children_w(REPOSITORIES_PATH, self.watcher()));
+
+        let mut result = vec![];
+
+        for child in children {
+            let path = format!("{}/{}", REPOSITORIES_PATH, child);
+
+            let (data, _stat) = match self.zk.get_data_w(&path, self.watcher()) {
+                Ok(d) => d,
+                Err(ZkError::NoNode) => {
+                    info!("repository node {} went away", path);
+                    continue;
+                },
+                Err(e) => return Err(RepoError::ZkError(e)),
+            };
+
+            match ZkRepository::new_from_node_data(&child, &data) {
+                Ok(mut zk_repo) => {
+                    zk_repo.indexed = try!(self.read_indexed(&child));
+
+                    if zk_repo.repo_type == "git" {
+                        result.push(zk_repo);
+                    } else {
+                        error!("ignoring repository {} of unsupported type {}", zk_repo.id, zk_repo.repo_type);
+                    }
+                },
+                Err(e) => {
+                    error!("ignoring repository node {}: {:?}", path, e);
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// the backed up indexed commit ids of a repository, if there are any
+    pub fn read_indexed(&self, zk_repo_id: &str) -> RepoResult<BTreeMap<String, String>> {
+        let path = format!("{}/{}", INDEXED_PATH, zk_repo_id);
+
+        match self.zk.get_data(&path, false) {
+            Ok((data, _stat)) => indexed_from_node_data(zk_repo_id, &data),
+            Err(ZkError::NoNode) => Ok(BTreeMap::new()),
+            Err(e) => Err(RepoError::ZkError(e)),
+        }
+    }
+
+    /// save the indexed commit id of each branch and tag in /indexed/{id},
+    /// so a replacement worker doesn't have to re-index everything.
+    /// it's kept apart from the repository node, which the operator owns and we're watching,
+    /// so backing up doesn't wake the worker or touch the configured branches and tags
+    pub fn backup_branches(&self, zk_repo_id: &str, db_branches: &Vec<db::RepoBranch>) -> RepoResult<()> {
+        let indexed: BTreeMap<String, String> = db_branches.iter()
+            .filter_map(|b| b.indexed_commit_id.as_ref().map(|c| (b.name.clone(), c.clone())))
+            .collect();
+
+        if indexed == try!(self.read_indexed(zk_repo_id)) {
+            return Ok(());
+        }
+
+        info!("backing up indexed commits of repository {} to zookeeper", zk_repo_id);
+
+        let path = format!("{}/{}", INDEXED_PATH, zk_repo_id);
+
+        let data = Json::Object(indexed.into_iter().map(|(name, commit_id)| (name, commit_id.to_json())).collect()).to_string().into_bytes();
+
+        match self.zk.set_data(&path, data.clone(), -1) {
+            Ok(_) => Ok(()),
+            Err(ZkError::NoNode) => {
+                try!(self.zk.create(&path, data, acls::OPEN_ACL_UNSAFE.clone(), CreateMode::Persistent));
+                Ok(())
+            },
+            Err(e) => Err(RepoError::ZkError(e)),
+        }
+    }
+
+    /// forget the backup of a repository that's no longer synced
+    pub fn remove_indexed(&self, zk_repo_id: &str) -> RepoResult<()> {
+        let path = format!("{}/{}", INDEXED_PATH, zk_repo_id);
+
+        match self.zk.delete(&path, -1) {
+            Ok(()) | Err(ZkError::NoNode) => Ok(()),
+            Err(e) => Err(RepoError::ZkError(e)),
+        }
+    }
+
+    /// drain pending watch notifications. returns true if the repository list may have changed.
+    /// fails if the session has expired, because then our lock node has gone and
+    /// another worker may already have taken over.
+    pub fn repositories_changed(&self) -> RepoResult<bool> {
+        let mut changed = false;
+
+        while let Ok(event) = self.events_rx.try_recv() {
+            match event {
+                ZkEvent::RepositoriesChanged => {
+                    changed = true;
+                },
+                ZkEvent::SessionExpired => {
+                    error!("zookeeper session expired");
+                    return Err(RepoError::ZkSessionExpired);
+                },
+                ZkEvent::LockNodeChanged => {}
+            }
+        }
+
+        Ok(changed)
+    }
+}
+
+impl Drop for Zk {
+    fn drop(&mut self) {
+        match self.release_worker_lock() {
+            Err(err) => info!("error releasing worker lock: {:?}", err),
+            _ => {}
+        }
+
+        info!("closing zookeeper session");
+        let _ = self.zk.close();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_parse_repository_node() {
+        let data = br#"{"type": "git", "url": "https://github.com/cmsd2/codelauf.git", "branches": ["master", {"name": "release", "indexed_commit_id": "abc"}, "feature/*"], "tags": ["v*"]}"#;
+
+        let zk_repo = ZkRepository::new_from_node_data("1", data).unwrap();
+
+        assert_eq!(zk_repo.url, "https://github.com/cmsd2/codelauf.git");
+        assert_eq!(zk_repo.branches.len(), 2);
+        assert_eq!(zk_repo.branches[0].name, "master");
+        assert_eq!(zk_repo.branches[1].indexed_commit_id, Some("abc".to_string()));
+        assert_eq!(zk_repo.branch_patterns, vec!["feature/*".to_string()]);
+        assert_eq!(zk_repo.tags, vec!["v*".to_string()]);
+    }
+
+    #[test]
+    pub fn test_parse_indexed_node() {
+        let indexed = indexed_from_node_data("1", br#"{"master": "def", "tags/v1.0": "123"}"#).unwrap();
+
+        assert_eq!(indexed.get("master"), Some(&"def".to_string()));
+        assert_eq!(indexed.get("tags/v1.0"), Some(&"123".to_string()));
+    }
+}